@@ -0,0 +1,114 @@
+//! Throughput of concurrent requests returning large results. The rows are
+//! converted inline on the former single threaded runtime, and by
+//! `rows_to_json` on the blocking pool on runtimes of growing size.
+//!
+//! The rows are fetched from the Postgres database of `DB_URI`, conversion
+//! and rendering of the response body are measured.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use serde_json::Value;
+use sqlx::PgPool;
+use sqlx::postgres::PgRow;
+use tokio::runtime::{Builder, Runtime};
+
+#[allow(dead_code)]
+#[path = "../src/endpoints/sql_utils/row_to_json.rs"]
+mod row_to_json;
+
+use row_to_json::{row_to_json, rows_to_json};
+
+const REQUESTS: usize = 32;
+/// Above the rows converted inline by `rows_to_json`.
+const ROWS: i64 = 2000;
+const QUERY: &str = "SELECT id, 'customer ' || id AS name, id * 1.25::float8 AS total,
+    jsonb_build_array('a', 'b', 'c') AS tags, now() AS created_at
+    FROM generate_series(1, $1::int8) AS id";
+
+async fn request(rows: Vec<PgRow>, inline: bool) -> usize {
+    let rows = if inline {
+        rows.iter().map(row_to_json).collect()
+    } else {
+        rows_to_json(rows).await.unwrap()
+    };
+
+    Value::Array(rows).to_string().len()
+}
+
+async fn requests(results: Vec<Vec<PgRow>>, inline: bool) {
+    let handles: Vec<_> = results
+        .into_iter()
+        .map(|rows| tokio::spawn(request(rows, inline)))
+        .collect();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+fn runtime(workers: Option<usize>) -> Runtime {
+    match workers {
+        None => Builder::new_current_thread().enable_all().build().unwrap(),
+        Some(workers) => Builder::new_multi_thread()
+            .worker_threads(workers)
+            .enable_all()
+            .build()
+            .unwrap(),
+    }
+}
+
+/// The rows of every request, fetched on the runtime owning the pool.
+fn fetch(db: &Runtime, pool: &PgPool) -> Vec<Vec<PgRow>> {
+    db.block_on(async {
+        let mut results = Vec::with_capacity(REQUESTS);
+        for _ in 0..REQUESTS {
+            results.push(sqlx::query(QUERY).bind(ROWS).fetch_all(pool).await.unwrap());
+        }
+
+        results
+    })
+}
+
+fn bench_runtime_scaling(c: &mut Criterion) {
+    let Ok(uri) = std::env::var("DB_URI") else {
+        eprintln!("DB_URI is not set, skipping the runtime scaling benchmark");
+        return;
+    };
+    let db = runtime(Some(1));
+    let pool = db.block_on(PgPool::connect(&uri)).unwrap();
+
+    let cpus = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let mut group = c.benchmark_group("large_results");
+    group.throughput(Throughput::Elements(REQUESTS as u64));
+    group.sample_size(20);
+
+    let rt = runtime(None);
+    for (name, inline) in [("inline", true), ("rows_to_json", false)] {
+        group.bench_function(BenchmarkId::new(name, "current_thread"), |b| {
+            b.iter_batched(
+                || fetch(&db, &pool),
+                |results| rt.block_on(requests(results, inline)),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    for workers in [1, 2, 4, 8].into_iter().filter(|w| *w <= cpus) {
+        let rt = runtime(Some(workers));
+        group.bench_with_input(
+            BenchmarkId::new("rows_to_json", format!("multi_thread/{}", workers)),
+            &workers,
+            |b, _| {
+                b.iter_batched(
+                    || fetch(&db, &pool),
+                    |results| rt.block_on(requests(results, false)),
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+
+    group.finish();
+    db.block_on(pool.close());
+}
+
+criterion_group!(benches, bench_runtime_scaling);
+criterion_main!(benches);
@@ -0,0 +1,203 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::HttpBody,
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use clap::ValueEnum;
+use log::info;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Longest `X-Request-Id` accepted from the client, a new one is generated
+/// for longer ones.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum AccessLogFormat {
+    Json,
+    Logfmt,
+}
+
+/// Id of the request being handled, `None` outside of a request, e.g. in
+/// the notification tasks.
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// `{"error": ...}` body carrying the id of the request, so that clients
+/// can report it.
+pub fn error_body(message: impl Into<Value>) -> String {
+    match request_id() {
+        Some(id) => json!({"error": message.into(), "request_id": id}),
+        None => json!({"error": message.into()}),
+    }
+    .to_string()
+}
+
+fn incoming_request_id(headers: &HeaderMap) -> Option<String> {
+    let id = headers.get(REQUEST_ID_HEADER)?.to_str().ok()?;
+
+    let valid = !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_graphic());
+
+    valid.then(|| id.to_string())
+}
+
+struct AccessRecord {
+    request_id: String,
+    method: String,
+    path: String,
+    /// route of the DSL endpoint, none for unmatched paths
+    endpoint: Option<String>,
+    status: u16,
+    duration: Duration,
+    /// unknown for streamed responses
+    bytes: Option<u64>,
+    remote: Option<IpAddr>,
+}
+
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty() && !value.contains([' ', '"', '=']) {
+        return value.to_string();
+    }
+
+    format!("{:?}", value)
+}
+
+impl AccessRecord {
+    fn format(&self, format: AccessLogFormat) -> String {
+        let duration_ms = self.duration.as_secs_f64() * 1000.0;
+
+        match format {
+            AccessLogFormat::Json => json!({
+                "request_id": self.request_id,
+                "method": self.method,
+                "path": self.path,
+                "endpoint": self.endpoint,
+                "status": self.status,
+                "duration_ms": duration_ms,
+                "bytes": self.bytes,
+                "remote": self.remote.map(|ip| ip.to_string()),
+            })
+            .to_string(),
+            AccessLogFormat::Logfmt => {
+                let optional = |v: Option<String>| v.map_or("-".to_string(), |v| logfmt_value(&v));
+                format!(
+                    "request_id={} method={} path={} endpoint={} status={} \
+                     duration_ms={:.3} bytes={} remote={}",
+                    logfmt_value(&self.request_id),
+                    self.method,
+                    logfmt_value(&self.path),
+                    optional(self.endpoint.clone()),
+                    self.status,
+                    duration_ms,
+                    optional(self.bytes.map(|b| b.to_string())),
+                    optional(self.remote.map(|ip| ip.to_string())),
+                )
+            }
+        }
+    }
+}
+
+/// Logs every request to the `access` target and makes its id, taken from
+/// `X-Request-Id` or generated, available to the handlers and the response.
+pub async fn access_log_middleware(
+    State(format): State<AccessLogFormat>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let request_id = match incoming_request_id(request.headers()) {
+        Some(id) => id,
+        None => {
+            let id = Uuid::new_v4().to_string();
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                request.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+            id
+        }
+    };
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let endpoint = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string());
+    let remote = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|c| c.0.ip());
+
+    let mut response = REQUEST_ID
+        .scope(request_id.clone(), next.run(request))
+        .await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    let record = AccessRecord {
+        request_id,
+        method,
+        path,
+        endpoint,
+        status: response.status().as_u16(),
+        duration: start.elapsed(),
+        bytes: response.body().size_hint().exact(),
+        remote,
+    };
+    info!(target: "access", "{}", record.format(format));
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_id() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(incoming_request_id(&headers), None);
+        headers.insert(REQUEST_ID_HEADER, "has space".parse().unwrap());
+        assert_eq!(incoming_request_id(&headers), None);
+        headers.insert(REQUEST_ID_HEADER, "abc-123".parse().unwrap());
+        assert_eq!(incoming_request_id(&headers).as_deref(), Some("abc-123"));
+
+        assert_eq!(error_body("boom"), r#"{"error":"boom"}"#);
+        let body = REQUEST_ID
+            .scope("abc-123".to_string(), async { error_body("boom") })
+            .await;
+        assert_eq!(body, r#"{"error":"boom","request_id":"abc-123"}"#);
+    }
+
+    #[test]
+    fn test_logfmt() {
+        let record = AccessRecord {
+            request_id: "abc".to_string(),
+            method: "GET".to_string(),
+            path: "/test/numbers".to_string(),
+            endpoint: None,
+            status: 200,
+            duration: Duration::from_millis(3),
+            bytes: Some(12),
+            remote: Some("127.0.0.1".parse().unwrap()),
+        };
+
+        assert_eq!(
+            record.format(AccessLogFormat::Logfmt),
+            "request_id=abc method=GET path=/test/numbers endpoint=- status=200 \
+             duration_ms=3.000 bytes=12 remote=127.0.0.1"
+        );
+    }
+}
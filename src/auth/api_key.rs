@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use axum::http::{HeaderMap, HeaderName};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{PgPool, Row};
+
+use crate::limits::rate_limit::{RateLimit, RateLimiter};
+
+/// Keys file looked up at the root of the DSL tree.
+pub const API_KEYS_FILE: &str = "api_keys.yml";
+
+/// A consumer authenticated by an API key. Only the sha256 hex digest of
+/// the key itself is ever stored.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ApiKey {
+    pub name: String,
+    pub hash: String,
+    /// projects the key may call, all when unset
+    pub projects: Option<Vec<String>>,
+    /// url paths the key may call, all when unset
+    pub endpoints: Option<Vec<String>>,
+    pub rate_limit: Option<RateLimit>,
+    /// claims the key holder is given, as if it came with a JWT
+    pub claims: Map<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct ApiKeysFile {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKey {
+    pub fn allows(&self, project: &str, url_path: &str) -> bool {
+        self.projects
+            .as_ref()
+            .is_none_or(|p| p.iter().any(|p| p == project))
+            && self
+                .endpoints
+                .as_ref()
+                .is_none_or(|e| e.iter().any(|e| e == url_path))
+    }
+
+    /// Claims of the key holder, `sub` defaults to the key name.
+    pub fn claims(&self) -> Value {
+        let mut claims = self.claims.clone();
+        claims
+            .entry("sub")
+            .or_insert_with(|| Value::String(self.name.clone()));
+
+        Value::Object(claims)
+    }
+}
+
+enum KeySource {
+    File(HashMap<String, ApiKey>),
+    /// query receiving the key hash as $1 and returning the columns
+    /// `name, projects, endpoints, rate_limit, claims`
+    Query(String, PgPool),
+}
+
+pub struct ApiKeyStore {
+    source: KeySource,
+    header: HeaderName,
+    limiter: RateLimiter,
+}
+
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn load_file(path: &Path) -> anyhow::Result<HashMap<String, ApiKey>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let file: ApiKeysFile = serde_yaml_ng::from_str(&content)
+        .with_context(|| format!("invalid API keys file {}", path.display()))?;
+
+    Ok(file
+        .keys
+        .into_iter()
+        .map(|k| (k.hash.to_lowercase(), k))
+        .collect())
+}
+
+impl ApiKeyStore {
+    pub fn from_args(
+        args: &crate::args::types::Args,
+        pool: &PgPool,
+    ) -> anyhow::Result<Option<ApiKeyStore>> {
+        let keys_file = Path::new(&args.dsl_path).join(API_KEYS_FILE);
+
+        let source = if let Some(query) = &args.api_keys_query {
+            KeySource::Query(query.clone(), pool.clone())
+        } else if keys_file.is_file() {
+            KeySource::File(load_file(&keys_file)?)
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(ApiKeyStore {
+            source,
+            header: HeaderName::try_from(args.api_key_header.as_str())?,
+            limiter: RateLimiter::default(),
+        }))
+    }
+
+    pub fn header(&self) -> &HeaderName {
+        &self.header
+    }
+
+    pub fn key_from_headers<'a>(&self, headers: &'a HeaderMap) -> Option<&'a str> {
+        headers.get(&self.header)?.to_str().ok()
+    }
+
+    pub async fn lookup(&self, key: &str) -> anyhow::Result<Option<ApiKey>> {
+        let hash = hash_key(key);
+
+        match &self.source {
+            KeySource::File(keys) => Ok(keys.get(&hash).cloned()),
+            KeySource::Query(query, pool) => {
+                let Some(row) = sqlx::query(query).bind(&hash).fetch_optional(pool).await? else {
+                    return Ok(None);
+                };
+
+                let rate_limit: Option<String> = row.try_get("rate_limit")?;
+                let claims: Option<Value> = row.try_get("claims")?;
+                Ok(Some(ApiKey {
+                    name: row.try_get("name")?,
+                    hash,
+                    projects: row.try_get("projects")?,
+                    endpoints: row.try_get("endpoints")?,
+                    rate_limit: rate_limit
+                        .map(|r| r.parse())
+                        .transpose()
+                        .map_err(anyhow::Error::msg)?,
+                    claims: match claims {
+                        Some(Value::Object(c)) => c,
+                        _ => Map::new(),
+                    },
+                }))
+            }
+        }
+    }
+
+    /// Applies the per key rate limit, returning the time to wait when the
+    /// key ran out of requests.
+    pub fn check_rate(&self, key: &ApiKey) -> Result<(), Duration> {
+        match &key.rate_limit {
+            Some(limit) => self.limiter.check(&key.name, limit),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let key = ApiKey {
+            name: "reporting".to_string(),
+            projects: Some(vec!["test".to_string()]),
+            ..Default::default()
+        };
+
+        assert!(key.allows("test", "/test/empty"));
+        assert!(!key.allows("test2", "/test2/empty"));
+        assert_eq!(key.claims()["sub"], "reporting");
+        assert_eq!(
+            hash_key("secret"),
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+use anyhow::{Context, anyhow};
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet, PublicKeyUse};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, decode, decode_header};
+use log::warn;
+use serde_json::Value;
+
+struct VerificationKey {
+    kid: Option<String>,
+    key: DecodingKey,
+    algorithms: Vec<Algorithm>,
+}
+
+/// Validates bearer tokens against the locally configured keys: a shared
+/// HS256 secret, a PEM public key and/or the keys of a JWKS file.
+pub struct JwtValidator {
+    keys: Vec<VerificationKey>,
+    audience: Option<String>,
+    issuer: Option<String>,
+    /// `--jwt-allow-no-exp`
+    allow_no_exp: bool,
+}
+
+fn pem_key(path: &str) -> anyhow::Result<VerificationKey> {
+    let pem = std::fs::read(path).with_context(|| format!("cannot read {}", path))?;
+
+    if let Ok(key) = DecodingKey::from_rsa_pem(&pem) {
+        return Ok(VerificationKey {
+            kid: None,
+            key,
+            algorithms: vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512],
+        });
+    }
+
+    let key = DecodingKey::from_ec_pem(&pem)
+        .with_context(|| format!("{} is neither an RSA nor an EC public key", path))?;
+    Ok(VerificationKey {
+        kid: None,
+        key,
+        algorithms: vec![Algorithm::ES256, Algorithm::ES384],
+    })
+}
+
+/// Signing keys of the JWKS file, the encryption keys an identity provider
+/// publishes along are skipped.
+fn jwks_keys(path: &str) -> anyhow::Result<Vec<VerificationKey>> {
+    let content = std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path))?;
+    let set: JwkSet =
+        serde_json::from_str(&content).with_context(|| format!("invalid JWKS in {}", path))?;
+
+    let mut keys = Vec::with_capacity(set.keys.len());
+    for jwk in set.keys.iter() {
+        let kid = jwk.common.key_id.as_deref().unwrap_or("without kid");
+        if jwk.common.public_key_use == Some(PublicKeyUse::Encryption) {
+            warn!("Skipping encryption key {} of {}", kid, path);
+            continue;
+        }
+        let algorithms = match jwk.common.key_algorithm {
+            Some(alg) => match format!("{:?}", alg).parse::<Algorithm>() {
+                Ok(alg) => vec![alg],
+                Err(_) => {
+                    warn!(
+                        "Skipping key {} of {}: {:?} is not a signing algorithm",
+                        kid, path, alg
+                    );
+                    continue;
+                }
+            },
+            None => match jwk.algorithm {
+                AlgorithmParameters::RSA(_) => {
+                    vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512]
+                }
+                AlgorithmParameters::EllipticCurve(_) => vec![Algorithm::ES256, Algorithm::ES384],
+                AlgorithmParameters::OctetKey(_) => vec![Algorithm::HS256],
+                AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+            },
+        };
+
+        keys.push(VerificationKey {
+            kid: jwk.common.key_id.clone(),
+            key: DecodingKey::from_jwk(jwk)?,
+            algorithms,
+        });
+    }
+
+    Ok(keys)
+}
+
+impl JwtValidator {
+    pub fn from_args(args: &crate::args::types::Args) -> anyhow::Result<Option<JwtValidator>> {
+        let mut keys = Vec::new();
+
+        if let Some(secret) = &args.jwt_secret {
+            keys.push(VerificationKey {
+                kid: None,
+                key: DecodingKey::from_secret(secret.as_bytes()),
+                algorithms: vec![Algorithm::HS256],
+            });
+        }
+        if let Some(path) = &args.jwt_public_key {
+            keys.push(pem_key(path)?);
+        }
+        if let Some(path) = &args.jwt_jwks {
+            keys.extend(jwks_keys(path)?);
+        }
+
+        if keys.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(JwtValidator {
+            keys,
+            audience: args.jwt_audience.clone(),
+            issuer: args.jwt_issuer.clone(),
+            allow_no_exp: args.jwt_allow_no_exp,
+        }))
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        // `exp` is always checked when present
+        if self.allow_no_exp {
+            validation.required_spec_claims.clear();
+        }
+
+        match &self.audience {
+            Some(aud) => validation.set_audience(&[aud]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(iss) = &self.issuer {
+            validation.set_issuer(&[iss]);
+        }
+
+        validation
+    }
+
+    /// Returns the claims of a valid token.
+    pub fn validate(&self, token: &str) -> anyhow::Result<Value> {
+        let header = decode_header(token)?;
+
+        let mut last_error = anyhow!("no key for algorithm {:?}", header.alg);
+        let candidates = self.keys.iter().filter(|k| {
+            k.algorithms.contains(&header.alg)
+                && match (&k.kid, &header.kid) {
+                    (Some(kid), Some(token_kid)) => kid == token_kid,
+                    _ => true,
+                }
+        });
+
+        for candidate in candidates {
+            match decode::<Value>(token, &candidate.key, &self.validation(header.alg)) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => last_error = e.into(),
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use jsonwebtoken::{EncodingKey, Header, encode};
+    use serde_json::json;
+
+    use super::*;
+    use crate::args::types::Args;
+
+    fn validator() -> JwtValidator {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl", "--jwt-secret", "secret"]);
+        JwtValidator::from_args(&args).unwrap().unwrap()
+    }
+
+    fn token(claims: Value, secret: &str) -> String {
+        encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret.as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate_hs256() {
+        let validator = validator();
+
+        let claims = validator
+            .validate(&token(
+                json!({"sub": "alice", "role": "admin", "exp": 4102444800u64}),
+                "secret",
+            ))
+            .unwrap();
+        assert_eq!(claims["sub"], "alice");
+
+        assert!(
+            validator
+                .validate(&token(json!({"sub": "alice"}), "other"))
+                .is_err()
+        );
+        assert!(
+            validator
+                .validate(&token(json!({"sub": "alice", "exp": 1}), "secret"))
+                .is_err()
+        );
+        assert!(validator.validate("not a token").is_err());
+        // tokens without exp would never expire
+        assert!(
+            validator
+                .validate(&token(json!({"sub": "alice"}), "secret"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_jwks_keys() {
+        let path = std::env::temp_dir().join(format!("rstsql-jwks-{}.json", std::process::id()));
+        let jwks = json!({"keys": [
+            {"kty": "oct", "kid": "sig", "alg": "HS256", "k": "c2VjcmV0"},
+            {"kty": "RSA", "kid": "enc", "use": "enc", "n": "AQAB", "e": "AQAB"},
+            {"kty": "RSA", "kid": "oaep", "alg": "RSA-OAEP", "n": "AQAB", "e": "AQAB"},
+        ]});
+        std::fs::write(&path, jwks.to_string()).unwrap();
+        let keys = jwks_keys(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        // the encryption keys must not fail the whole set
+        let keys = keys.unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].kid.as_deref(), Some("sig"));
+        assert_eq!(keys[0].algorithms, vec![Algorithm::HS256]);
+    }
+}
@@ -0,0 +1,367 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use clap::ValueEnum;
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::PgPool;
+
+use crate::access_log::error_body;
+use crate::auth::api_key::{ApiKey, ApiKeyStore};
+use crate::auth::jwt::JwtValidator;
+use crate::auth::policy::Policy;
+use crate::limits::rate_limit::retry_after_header;
+
+pub mod api_key;
+pub mod jwt;
+pub mod policy;
+
+/// Per endpoint authentication requirement, `auth:` in the declaration.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// credentials are ignored
+    None,
+    /// anonymous calls are allowed, but provided credentials must be valid
+    Optional,
+    /// valid credentials are required
+    Required,
+}
+
+/// Authenticated caller, stored in the request extensions by the auth layer.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub claims: Value,
+    /// API key the caller authenticated with
+    pub api_key: Option<Arc<ApiKey>>,
+}
+
+impl Identity {
+    /// Time left until the `exp` claim, for sessions outliving the request
+    /// that authenticated them.
+    pub fn expires_in(&self) -> Option<Duration> {
+        let exp = self.claims.get("exp").and_then(Value::as_u64)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Some(Duration::from_secs(exp.saturating_sub(now)))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expires_in().is_some_and(|left| left.is_zero())
+    }
+}
+
+#[derive(Debug)]
+pub struct AuthError {
+    status: StatusCode,
+    message: String,
+    retry_after: Option<Duration>,
+}
+
+impl AuthError {
+    fn new(message: String) -> Self {
+        Self {
+            status: StatusCode::UNAUTHORIZED,
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn forbidden(message: String) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn too_many_requests(retry_after: Duration) -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            message: "rate limit exceeded".to_string(),
+            retry_after: Some(retry_after),
+        }
+    }
+
+    fn internal(message: String) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, error_body(self.message)).into_response();
+
+        if self.status == StatusCode::UNAUTHORIZED {
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        }
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after_header(retry_after));
+        }
+
+        response
+    }
+}
+
+pub struct Authenticator {
+    jwt: Option<JwtValidator>,
+    api_keys: Option<ApiKeyStore>,
+    default_mode: AuthMode,
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+impl Authenticator {
+    pub fn from_args(
+        args: &crate::args::types::Args,
+        pool: &PgPool,
+    ) -> anyhow::Result<Authenticator> {
+        let jwt = JwtValidator::from_args(args)?;
+        let api_keys = ApiKeyStore::from_args(args, pool)?;
+        if jwt.is_none() && api_keys.is_none() && args.auth_default == AuthMode::Required {
+            warn!("Authentication is required by default, but neither JWT keys nor API keys are configured");
+        }
+
+        Ok(Authenticator {
+            jwt,
+            api_keys,
+            default_mode: args.auth_default,
+        })
+    }
+
+    pub fn mode(&self, declared: Option<AuthMode>) -> AuthMode {
+        declared.unwrap_or(self.default_mode)
+    }
+
+    pub fn jwt_enabled(&self) -> bool {
+        self.jwt.is_some()
+    }
+
+    /// Header carrying the API key, when API keys are configured.
+    pub fn api_key_header(&self) -> Option<&str> {
+        self.api_keys.as_ref().map(|k| k.header().as_str())
+    }
+
+    async fn authenticate_api_key(
+        &self,
+        store: &ApiKeyStore,
+        key: &str,
+    ) -> Result<Identity, AuthError> {
+        let key = match store.lookup(key).await {
+            Ok(Some(k)) => k,
+            Ok(None) => return Err(AuthError::new("invalid API key".to_string())),
+            Err(e) => {
+                warn!("API key lookup failed: {}", e);
+                return Err(AuthError::internal("API key lookup failed".to_string()));
+            }
+        };
+
+        store
+            .check_rate(&key)
+            .map_err(AuthError::too_many_requests)?;
+
+        Ok(Identity {
+            claims: key.claims(),
+            api_key: Some(Arc::new(key)),
+        })
+    }
+
+    async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        mode: AuthMode,
+    ) -> Result<Option<Identity>, AuthError> {
+        if mode == AuthMode::None {
+            return Ok(None);
+        }
+
+        if let Some(store) = &self.api_keys
+            && let Some(key) = store.key_from_headers(headers)
+        {
+            return self
+                .authenticate_api_key(store, key)
+                .await
+                .map(Some);
+        }
+
+        // without JWT keys the Authorization header is ignored, as it was
+        // before authentication existed
+        let Some((jwt, token)) = self
+            .jwt
+            .as_ref()
+            .and_then(|jwt| Some((jwt, bearer_token(headers)?)))
+        else {
+            return match mode {
+                AuthMode::Required => Err(AuthError::new("authentication required".to_string())),
+                _ => Ok(None),
+            };
+        };
+
+        match jwt.validate(token) {
+            Ok(claims) => Ok(Some(Identity {
+                claims,
+                api_key: None,
+            })),
+            Err(e) => Err(AuthError::new(format!("invalid token: {}", e))),
+        }
+    }
+}
+
+/// State of the auth layer wrapped around a single endpoint handler.
+#[derive(Clone)]
+pub struct EndpointAuth {
+    authenticator: Arc<Authenticator>,
+    mode: AuthMode,
+    policy: Option<Arc<Policy>>,
+    project: String,
+    url_path: String,
+    /// off for the websocket handshake, whose messages target endpoints
+    /// that are authorized one by one
+    check_scope: bool,
+}
+
+impl EndpointAuth {
+    pub fn new(
+        authenticator: Arc<Authenticator>,
+        declared: Option<AuthMode>,
+        project: &str,
+        url_path: &str,
+    ) -> EndpointAuth {
+        let mode = authenticator.mode(declared);
+
+        EndpointAuth {
+            authenticator,
+            mode,
+            policy: None,
+            project: project.to_string(),
+            url_path: url_path.to_string(),
+            check_scope: true,
+        }
+    }
+
+    pub fn handshake(authenticator: Arc<Authenticator>, url_path: &str) -> EndpointAuth {
+        EndpointAuth {
+            check_scope: false,
+            ..EndpointAuth::new(authenticator, None, "", url_path)
+        }
+    }
+
+    /// Authorization rules come with `AuthMode::Required`, see
+    /// `Declaration::access`.
+    pub fn with_policy(mut self, policy: Option<Policy>) -> EndpointAuth {
+        self.policy = policy.map(Arc::new);
+
+        self
+    }
+
+    /// Checks an already authenticated caller against the endpoint.
+    pub fn authorize(&self, identity: Option<&Identity>) -> Result<(), AuthError> {
+        let Some(identity) = identity else {
+            return match self.mode {
+                AuthMode::Required => Err(AuthError::new("authentication required".to_string())),
+                _ => Ok(()),
+            };
+        };
+        if self.mode == AuthMode::None {
+            return Ok(());
+        }
+
+        if self.check_scope
+            && let Some(key) = &identity.api_key
+            && !key.allows(&self.project, &self.url_path)
+        {
+            return Err(AuthError::forbidden(format!(
+                "API key {} is not allowed to call {}",
+                key.name, self.url_path
+            )));
+        }
+
+        match &self.policy {
+            Some(policy) => policy.check(identity).map_err(AuthError::forbidden),
+            None => Ok(()),
+        }
+    }
+}
+
+pub async fn auth_middleware(
+    State(auth): State<EndpointAuth>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let identity = match auth
+        .authenticator
+        .authenticate(request.headers(), auth.mode)
+        .await
+    {
+        Ok(identity) => identity,
+        Err(e) => return e.into_response(),
+    };
+    if let Err(e) = auth.authorize(identity.as_ref()) {
+        return e.into_response();
+    }
+
+    if let Some(identity) = identity {
+        debug!(
+            "{} authenticated as {}",
+            request.uri(),
+            identity.claims["sub"]
+        );
+        request.extensions_mut().insert(identity);
+    }
+
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_expires_in() {
+        let identity = |claims: Value| Identity {
+            claims,
+            api_key: None,
+        };
+
+        assert!(identity(json!({"exp": 1})).expired());
+        let left = identity(json!({"exp": 4102444800u64})).expires_in().unwrap();
+        assert!(left > Duration::from_secs(3600));
+        assert!(identity(json!({"sub": "alice"})).expires_in().is_none());
+    }
+}
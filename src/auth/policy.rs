@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use serde_json::Value;
+
+use crate::auth::Identity;
+
+/// Coarse authorization checked by the auth layer before the endpoint SQL
+/// runs: the caller must hold one of the allowed roles and satisfy every
+/// claim predicate.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    role_claim: String,
+    allow_roles: Option<Vec<String>>,
+    /// dotted claim path -> expected value, or list of accepted values
+    require_claims: BTreeMap<String, Value>,
+}
+
+/// Claim at a dotted path, e.g. `realm_access.role`.
+pub(crate) fn claim<'a>(claims: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(claims, |v, key| v.get(key))
+}
+
+/// A claim holding a list matches when any of its items does.
+fn matches(actual: &Value, expected: &Value) -> bool {
+    match (actual, expected) {
+        (Value::Array(items), _) => items.iter().any(|i| matches(i, expected)),
+        (_, Value::Array(accepted)) => accepted.contains(actual),
+        _ => actual == expected,
+    }
+}
+
+impl Policy {
+    pub fn new(
+        role_claim: &str,
+        allow_roles: Option<Vec<String>>,
+        require_claims: Option<BTreeMap<String, Value>>,
+    ) -> Option<Policy> {
+        if allow_roles.is_none() && require_claims.is_none() {
+            return None;
+        }
+
+        Some(Policy {
+            role_claim: role_claim.to_string(),
+            allow_roles,
+            require_claims: require_claims.unwrap_or_default(),
+        })
+    }
+
+    /// Returns the reason the identity is rejected.
+    pub fn check(&self, identity: &Identity) -> Result<(), String> {
+        if let Some(allowed) = &self.allow_roles {
+            let roles = Value::Array(allowed.iter().cloned().map(Value::String).collect());
+            let granted =
+                claim(&identity.claims, &self.role_claim).is_some_and(|role| matches(role, &roles));
+            if !granted {
+                return Err(format!(
+                    "one of the roles {} is required",
+                    allowed.join(", ")
+                ));
+            }
+        }
+
+        for (path, expected) in &self.require_claims {
+            if !claim(&identity.claims, path).is_some_and(|c| matches(c, expected)) {
+                return Err(format!("claim {} does not match {}", path, expected));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn identity(claims: Value) -> Identity {
+        Identity {
+            claims,
+            api_key: None,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let policy = Policy::new(
+            "role",
+            Some(vec!["admin".to_string(), "analyst".to_string()]),
+            Some(BTreeMap::from([
+                ("org.id".to_string(), json!(7)),
+                ("scope".to_string(), json!(["read", "write"])),
+            ])),
+        )
+        .unwrap();
+
+        let allowed = |claims: Value| policy.check(&identity(claims)).is_ok();
+
+        assert!(allowed(
+            json!({"role": "analyst", "org": {"id": 7}, "scope": "read"})
+        ));
+        assert!(allowed(
+            json!({"role": ["viewer", "admin"], "org": {"id": 7}, "scope": ["write"]})
+        ));
+        assert!(!allowed(
+            json!({"role": "viewer", "org": {"id": 7}, "scope": "read"})
+        ));
+        assert!(!allowed(
+            json!({"role": "admin", "org": {"id": 8}, "scope": "read"})
+        ));
+        assert!(!allowed(json!({"role": "admin"})));
+        assert!(Policy::new("role", None, None).is_none());
+    }
+}
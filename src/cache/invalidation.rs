@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::cache::ResponseCache;
+use crate::notify::{NotifyEvent, NotifyHub};
+
+/// Evicts the cached responses of the endpoints listening to a channel
+/// whenever a notification arrives on it. When notifications may have been
+/// missed every one of those endpoints is evicted.
+pub fn spawn_invalidation(
+    cache: Arc<ResponseCache>,
+    hub: &NotifyHub,
+    endpoints_by_channel: HashMap<String, Vec<String>>,
+) {
+    if endpoints_by_channel.is_empty() {
+        return;
+    }
+
+    let mut events = hub.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let endpoints: Vec<&String> = match events.recv().await {
+                Ok(NotifyEvent::Notification { channel, payload }) => {
+                    debug!(
+                        "Invalidating the responses cached for channel {} ({})",
+                        channel, payload
+                    );
+                    endpoints_by_channel
+                        .get(&channel)
+                        .into_iter()
+                        .flatten()
+                        .collect()
+                }
+                Ok(NotifyEvent::Reconnected) | Err(RecvError::Lagged(_)) => {
+                    endpoints_by_channel.values().flatten().collect()
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            for endpoint in endpoints {
+                cache.invalidate(endpoint);
+            }
+        }
+    });
+}
@@ -0,0 +1,157 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use lru::LruCache;
+
+pub mod invalidation;
+
+/// Cached response of an endpoint for one set of parameter values.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// `METHOD /url/path`
+    pub endpoint: String,
+    pub params: String,
+}
+
+impl CacheKey {
+    fn size(&self) -> usize {
+        self.endpoint.len() + self.params.len()
+    }
+}
+
+struct Entry {
+    body: Bytes,
+    stored: Instant,
+    ttl: Duration,
+}
+
+pub struct Hit {
+    pub body: Bytes,
+    pub age: Duration,
+    pub ttl: Duration,
+}
+
+struct Entries {
+    lru: LruCache<CacheKey, Entry>,
+    bytes: usize,
+}
+
+/// In-memory response bodies shared by every endpoint, the least recently
+/// used ones are evicted once `max_bytes` is exceeded.
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    max_bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize) -> ResponseCache {
+        ResponseCache {
+            entries: Mutex::new(Entries {
+                lru: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            max_bytes,
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, key: &CacheKey) -> Option<Hit> {
+        let mut entries = self.lock();
+        let entry = entries.lru.get(key)?;
+
+        let age = entry.stored.elapsed();
+        if age < entry.ttl {
+            return Some(Hit {
+                body: entry.body.clone(),
+                age,
+                ttl: entry.ttl,
+            });
+        }
+
+        if let Some(expired) = entries.lru.pop(key) {
+            entries.bytes -= key.size() + expired.body.len();
+        }
+        None
+    }
+
+    pub fn put(&self, key: CacheKey, body: Bytes, ttl: Duration) {
+        let size = key.size() + body.len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.lock();
+        let entry = Entry {
+            body,
+            stored: Instant::now(),
+            ttl,
+        };
+        if let Some((old_key, old)) = entries.lru.push(key, entry) {
+            entries.bytes -= old_key.size() + old.body.len();
+        }
+        entries.bytes += size;
+
+        while entries.bytes > self.max_bytes {
+            let Some((old_key, old)) = entries.lru.pop_lru() else {
+                break;
+            };
+            entries.bytes -= old_key.size() + old.body.len();
+        }
+    }
+
+    /// Drops every cached response of the endpoint.
+    pub fn invalidate(&self, endpoint: &str) {
+        let mut entries = self.lock();
+        let keys: Vec<CacheKey> = entries
+            .lru
+            .iter()
+            .map(|(k, _)| k)
+            .filter(|k| k.endpoint == endpoint)
+            .cloned()
+            .collect();
+
+        for key in keys {
+            if let Some(old) = entries.lru.pop(&key) {
+                entries.bytes -= key.size() + old.body.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(params: &str) -> CacheKey {
+        CacheKey {
+            endpoint: "GET /test/numbers".to_string(),
+            params: params.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_put_get() {
+        let cache = ResponseCache::new(2 * (key("1").size() + 4));
+        let ttl = Duration::from_secs(60);
+
+        cache.put(key("1"), Bytes::from("[1] "), ttl);
+        cache.put(key("2"), Bytes::from("[2] "), ttl);
+        assert!(cache.get(&key("1")).is_some());
+
+        // "2" is the least recently used
+        cache.put(key("3"), Bytes::from("[3] "), ttl);
+        assert!(cache.get(&key("2")).is_none());
+        assert_eq!(cache.get(&key("1")).unwrap().body, Bytes::from("[1] "));
+        assert_eq!(cache.get(&key("3")).unwrap().body, Bytes::from("[3] "));
+
+        cache.put(key("4"), Bytes::from("[4] "), Duration::ZERO);
+        assert!(cache.get(&key("4")).is_none());
+
+        cache.invalidate("GET /test/numbers");
+        assert!(cache.get(&key("3")).is_none());
+    }
+}
@@ -0,0 +1,253 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use log::{info, warn};
+use serde::Deserialize;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Executor, PgPool};
+
+pub const DATASOURCES_FILE: &str = "datasources.yml";
+/// Name of the `--db-uri` datasource, used by the endpoints choosing none.
+pub const DEFAULT_DATASOURCE: &str = "default";
+
+/// Postgres 14 aborts the query of a client that went away on its own, see
+/// `client_connection_check_interval`.
+const CLIENT_CHECK_VERSION: u32 = 140000;
+const CLIENT_CHECK_INTERVAL: &str = "1s";
+/// Set once the check interval could not be set on a connection, e.g.
+/// behind a pooler rejecting it, the queries are then cancelled by pid.
+static CLIENT_CHECK_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the server aborts the query of a connection closed by the client,
+/// otherwise it has to be cancelled with `pg_cancel_backend`.
+pub fn checks_client(server_version: Option<u32>) -> bool {
+    server_version.is_some_and(|v| v >= CLIENT_CHECK_VERSION)
+        && !CLIENT_CHECK_FAILED.load(Ordering::Relaxed)
+}
+
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Delay before the given retry, doubling from half a second up to 30s.
+fn backoff(retry: u32) -> Duration {
+    FIRST_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(retry))
+        .min(MAX_RETRY_DELAY)
+}
+
+#[derive(Debug, Deserialize)]
+struct DatasourcesFile {
+    datasources: BTreeMap<String, DatasourceConfig>,
+}
+
+/// Named database declared in `datasources.yml`, unset pool settings fall
+/// back to the `--db-*` arguments.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DatasourceConfig {
+    uri: Option<String>,
+    /// environment variable holding the uri, keeps the credentials out of
+    /// the DSL tree
+    uri_env: Option<String>,
+    max_connections: Option<u32>,
+    min_connections: Option<u32>,
+    after_connect: Option<String>,
+}
+
+impl DatasourceConfig {
+    fn uri(&self, name: &str) -> anyhow::Result<String> {
+        match (&self.uri, &self.uri_env) {
+            (Some(uri), None) => Ok(uri.clone()),
+            (None, Some(var)) => std::env::var(var)
+                .with_context(|| format!("datasource {}: cannot read {}", name, var)),
+            _ => bail!("datasource {} needs either uri or uri_env", name),
+        }
+    }
+}
+
+fn load_file(path: &Path) -> anyhow::Result<BTreeMap<String, DatasourceConfig>> {
+    let content =
+        std::fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+    let file: DatasourcesFile = serde_yaml_ng::from_str(&content)
+        .with_context(|| format!("invalid datasources file {}", path.display()))?;
+
+    if file.datasources.contains_key(DEFAULT_DATASOURCE) {
+        bail!(
+            "{}: the datasource name {} is taken by --db-uri",
+            path.display(),
+            DEFAULT_DATASOURCE
+        );
+    }
+
+    Ok(file.datasources)
+}
+
+fn pool_options(args: &crate::args::types::Args, config: &DatasourceConfig) -> PgPoolOptions {
+    let after_connect = config
+        .after_connect
+        .clone()
+        .or_else(|| args.db_after_connect.clone());
+
+    PgPoolOptions::new()
+        .max_connections(config.max_connections.unwrap_or(args.db_max_connections))
+        .min_connections(config.min_connections.unwrap_or(args.db_min_connections))
+        .acquire_timeout(args.db_acquire_timeout)
+        .idle_timeout(args.db_idle_timeout)
+        .max_lifetime(args.db_max_lifetime)
+        .after_connect(move |conn, _meta| {
+            let after_connect = after_connect.clone();
+            Box::pin(async move {
+                if conn
+                    .server_version_num()
+                    .is_some_and(|v| v >= CLIENT_CHECK_VERSION)
+                {
+                    let res = sqlx::query(
+                        "SELECT set_config('client_connection_check_interval', $1, false)",
+                    )
+                    .bind(CLIENT_CHECK_INTERVAL)
+                    .execute(&mut *conn)
+                    .await;
+                    if let Err(e) = res
+                        && !CLIENT_CHECK_FAILED.swap(true, Ordering::Relaxed)
+                    {
+                        warn!(
+                            "Cannot set client_connection_check_interval ({}), dropped requests are cancelled with pg_cancel_backend",
+                            e
+                        );
+                    }
+                }
+                if let Some(sql) = after_connect {
+                    conn.execute(sqlx::raw_sql(&sql)).await?;
+                }
+                Ok(())
+            })
+        })
+}
+
+/// Connects the pool, retrying with backoff while Postgres is not reachable
+/// yet, e.g. when both are started together.
+async fn connect(
+    uri: &str,
+    args: &crate::args::types::Args,
+    config: &DatasourceConfig,
+) -> Result<PgPool, sqlx::Error> {
+    let mut retry = 0;
+
+    loop {
+        match pool_options(args, config).connect(uri).await {
+            Ok(pool) => return Ok(pool),
+            Err(e) if retry < args.db_connect_retries => {
+                let delay = backoff(retry);
+                warn!(
+                    "Cannot connect to the database ({}), retrying in {:?}",
+                    e, delay
+                );
+                tokio::time::sleep(delay).await;
+                retry += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Pools of the `--db-uri` database and of the ones declared in
+/// `datasources.yml`, by name. Cheap to clone, this is the router state.
+#[derive(Debug, Clone)]
+pub struct Datasources {
+    pools: Arc<BTreeMap<String, PgPool>>,
+}
+
+impl Datasources {
+    pub async fn connect(args: &crate::args::types::Args) -> anyhow::Result<Datasources> {
+        let file = Path::new(&args.dsl_path).join(DATASOURCES_FILE);
+        let configs = if file.is_file() {
+            load_file(&file)?
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut pools = BTreeMap::new();
+        pools.insert(
+            DEFAULT_DATASOURCE.to_string(),
+            connect(&args.db_uri, args, &DatasourceConfig::default()).await?,
+        );
+        for (name, config) in configs {
+            let pool = connect(&config.uri(&name)?, args, &config)
+                .await
+                .with_context(|| format!("datasource {}", name))?;
+            info!("Connected datasource {}", name);
+            pools.insert(name, pool);
+        }
+
+        Ok(Datasources {
+            pools: Arc::new(pools),
+        })
+    }
+
+    /// Pool of the `--db-uri` database.
+    pub fn default_pool(&self) -> &PgPool {
+        &self.pools[DEFAULT_DATASOURCE]
+    }
+
+    /// `None` stands for the default datasource.
+    pub fn get(&self, name: Option<&str>) -> Option<&PgPool> {
+        self.pools.get(name.unwrap_or(DEFAULT_DATASOURCE))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &PgPool)> {
+        self.pools.iter().map(|(name, pool)| (name.as_str(), pool))
+    }
+
+    pub async fn close(&self) {
+        futures_util::future::join_all(self.pools.values().map(|p| p.close())).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checks_client() {
+        assert!(!checks_client(Some(130000)));
+        assert!(!checks_client(None));
+        assert!(checks_client(Some(CLIENT_CHECK_VERSION)));
+
+        CLIENT_CHECK_FAILED.store(true, Ordering::Relaxed);
+        assert!(!checks_client(Some(CLIENT_CHECK_VERSION)));
+        CLIENT_CHECK_FAILED.store(false, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), Duration::from_millis(500));
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(3), Duration::from_secs(4));
+        assert_eq!(backoff(6), MAX_RETRY_DELAY);
+        assert_eq!(backoff(100), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_datasources_file() {
+        let file: DatasourcesFile = serde_yaml_ng::from_str(
+            "datasources:\n  \
+               warehouse:\n    uri: postgresql://warehouse\n    max_connections: 4\n  \
+               unset:\n    uri_env: RSTSQL_TEST_UNSET_URI\n  \
+               broken:\n    uri: postgresql://a\n    uri_env: B\n",
+        )
+        .unwrap();
+
+        let warehouse = &file.datasources["warehouse"];
+        assert_eq!(
+            warehouse.uri("warehouse").unwrap(),
+            "postgresql://warehouse"
+        );
+        assert_eq!(warehouse.max_connections, Some(4));
+        assert!(file.datasources["unset"].uri("unset").is_err());
+        assert!(file.datasources["broken"].uri("broken").is_err());
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::{Value, json};
+use sqlx::PgPool;
+
+use crate::cache::{CacheKey, ResponseCache};
+use crate::endpoints::context::{RequestContext, is_context_param};
+use crate::endpoints::declaration::CacheConfig;
+use crate::endpoints::handler::EndpointHandler;
+
+/// Response cache of a single GET endpoint.
+#[derive(Clone)]
+pub struct EndpointCache {
+    cache: Arc<ResponseCache>,
+    endpoint: String,
+    ttl: Duration,
+    vary: Vec<String>,
+    /// see `Access::authenticates`
+    authenticated: bool,
+}
+
+fn bypass(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|d| matches!(d.trim(), "no-cache" | "no-store"))
+}
+
+fn cached_response(body: Bytes, private: bool, ttl: Duration, age: Duration) -> Response {
+    let max_age = ttl.saturating_sub(age).as_secs();
+    // responses depending on the caller must not end up in shared caches
+    let cache_control = if private {
+        format!("private, max-age={}", max_age)
+    } else {
+        format!("max-age={}", max_age)
+    };
+
+    (
+        [
+            (
+                header::CONTENT_TYPE,
+                "text/plain; charset=utf-8".to_string(),
+            ),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+impl EndpointCache {
+    pub fn new(
+        cache: Arc<ResponseCache>,
+        endpoint: String,
+        config: &CacheConfig,
+        authenticated: bool,
+    ) -> Self {
+        EndpointCache {
+            cache,
+            endpoint,
+            ttl: config.ttl,
+            vary: config.vary.clone(),
+            authenticated,
+        }
+    }
+
+    /// Parameters the response depends on: the bound and query syntax ones
+    /// from the query string, the context ones the SQL and the embeds use,
+    /// the `vary` list, which may name claims and headers the SQL does not
+    /// use, and the role and claims row-level security applies.
+    fn key(
+        &self,
+        handler: &EndpointHandler,
+        query: &HashMap<String, String>,
+        context: &RequestContext,
+    ) -> CacheKey {
+        let mut params: BTreeMap<String, Value> = query
+            .iter()
+            .filter(|(k, _)| handler.depends_on(k))
+            .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+            .collect();
+
+        let (context_vary, query_vary): (Vec<String>, Vec<String>) =
+            self.vary.iter().cloned().partition(|v| is_context_param(v));
+        for name in query_vary {
+            let value = query
+                .get(&name)
+                .map_or(Value::Null, |v| Value::String(v.clone()));
+            params.insert(name, value);
+        }
+        let context_params: Vec<String> = handler.all_params().cloned().collect();
+        params.extend(context.resolve_params(&context_params));
+        params.extend(context.resolve_params(&context_vary));
+
+        // the query fails without a role, so the settings do not need to
+        // tell the error apart
+        let rls = handler
+            .rls()
+            .and_then(|rls| rls.settings(context).ok())
+            .map(|(claims, role)| json!({"role": role, "claims": claims}));
+
+        CacheKey {
+            endpoint: self.endpoint.clone(),
+            params: json!({"params": params, "rls": rls}).to_string(),
+        }
+    }
+
+    /// Responses depending on the caller, or only served to some callers.
+    /// Shared caches may store responses to an API key, which is not sent
+    /// in the `Authorization` header.
+    fn private(&self, handler: &EndpointHandler) -> bool {
+        self.authenticated
+            || handler.rls().is_some()
+            || handler.all_params().any(|p| is_context_param(p))
+            || self.vary.iter().any(|v| is_context_param(v))
+    }
+
+    /// Serves the cached response unless the client asked for a fresh one
+    /// with `Cache-Control: no-cache`, the fresh response is cached anyway.
+    pub async fn handle_get(
+        &self,
+        handler: &EndpointHandler,
+        query: &HashMap<String, String>,
+        context: &RequestContext,
+        pool: PgPool,
+    ) -> anyhow::Result<Response> {
+        let key = self.key(handler, query, context);
+        let private = self.private(handler);
+
+        if !bypass(&context.headers)
+            && let Some(hit) = self.cache.get(&key)
+        {
+            let mut response = cached_response(hit.body, private, hit.ttl, hit.age);
+            response
+                .headers_mut()
+                .insert(header::AGE, HeaderValue::from(hit.age.as_secs()));
+            return Ok(response);
+        }
+
+        let body = Bytes::from(handler.handle_get(query, context, pool).await?.to_string());
+        self.cache.put(key, body.clone(), self.ttl);
+
+        Ok(cached_response(body, private, self.ttl, Duration::ZERO))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::types::Args;
+    use crate::auth::Identity;
+    use crate::endpoints::declaration::Declaration;
+    use crate::endpoints::parser::{
+        Endpoint, EndpointCollections, EndpointIndex, EndpointMethod, Project,
+    };
+
+    fn caller(role: &str) -> RequestContext {
+        RequestContext {
+            identity: Some(Identity {
+                claims: json!({"role": role}),
+                api_key: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_rls_key() {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl", "--rls"]);
+        let collection = EndpointCollections {
+            projects: vec![Project {
+                project_name: "shop".to_string(),
+                endpoints: vec![Endpoint {
+                    tag: "shop".to_string(),
+                    method: EndpointMethod::GET,
+                    url_path: "/shop/orders".to_string(),
+                    file_path: "shop/GET/orders.sql".to_string(),
+                    file_content: "SELECT * FROM orders".to_string(),
+                    schema: "".to_string(),
+                    declaration: Declaration::default(),
+                }],
+                config: Default::default(),
+            }],
+        };
+        let index = EndpointIndex::new(&collection);
+        let project = &collection.projects[0];
+        let handler = EndpointHandler::new(&project.endpoints[0], &project.config, &args, &index);
+        let cache = EndpointCache::new(
+            Arc::new(ResponseCache::new(1024)),
+            "GET /shop/orders".to_string(),
+            &CacheConfig {
+                ttl: Duration::from_secs(60),
+                vary: vec![],
+            },
+            false,
+        );
+
+        // the SQL uses no parameter, but Postgres filters the rows by role
+        let query = HashMap::new();
+        assert_ne!(
+            cache.key(&handler, &query, &caller("alice")),
+            cache.key(&handler, &query, &caller("bob"))
+        );
+        assert!(cache.private(&handler));
+    }
+
+    #[test]
+    fn test_private() {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl"]);
+        let collection = EndpointCollections::parse_from_dir(&args.dsl_path);
+        let index = EndpointIndex::new(&collection);
+        let numbers = index.get_endpoints["/test/numbers"];
+        let handler = EndpointHandler::new(numbers, index.project(numbers), &args, &index);
+        let config = CacheConfig {
+            ttl: Duration::from_secs(60),
+            vary: vec![],
+        };
+        let cache = |authenticated| {
+            EndpointCache::new(
+                Arc::new(ResponseCache::new(1024)),
+                "GET /test/numbers".to_string(),
+                &config,
+                authenticated,
+            )
+        };
+
+        assert!(!cache(false).private(&handler));
+        assert!(cache(true).private(&handler));
+    }
+}
@@ -0,0 +1,91 @@
+use std::ops::{Deref, DerefMut};
+
+use log::{debug, warn};
+use sqlx::pool::PoolConnection;
+use sqlx::{PgConnection, PgPool, Postgres};
+
+use crate::access_log::request_id;
+use crate::db::checks_client;
+
+/// Connection of an in-flight request. axum drops the request future when
+/// the client goes away, if that happens before `finish` the running query
+/// is cancelled and the connection is closed instead of going back to the
+/// pool in an unknown state.
+pub struct CancelOnDrop {
+    conn: PoolConnection<Postgres>,
+    pool: PgPool,
+    /// backend to cancel, none when the server aborts the query itself once
+    /// the connection is closed
+    pid: Option<i32>,
+    done: bool,
+}
+
+impl CancelOnDrop {
+    pub async fn acquire(pool: &PgPool) -> sqlx::Result<CancelOnDrop> {
+        let mut conn = pool.acquire().await?;
+        // sqlx keeps the backend key of the connection to itself
+        let pid = if checks_client(conn.server_version_num()) {
+            None
+        } else {
+            Some(
+                sqlx::query_scalar("SELECT pg_backend_pid()")
+                    .fetch_one(&mut *conn)
+                    .await?,
+            )
+        };
+
+        Ok(CancelOnDrop {
+            conn,
+            pool: pool.clone(),
+            pid,
+            done: false,
+        })
+    }
+
+    /// Hands the connection back to the pool as is.
+    pub fn finish(mut self) {
+        self.done = true;
+    }
+}
+
+impl Deref for CancelOnDrop {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        &self.conn
+    }
+}
+
+impl DerefMut for CancelOnDrop {
+    fn deref_mut(&mut self) -> &mut PgConnection {
+        &mut self.conn
+    }
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        debug!(
+            "request {} dropped, cancelling its query",
+            request_id().unwrap_or_default()
+        );
+        self.conn.close_on_drop();
+
+        let Some(pid) = self.pid else {
+            return;
+        };
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let res = sqlx::query("SELECT pg_cancel_backend($1)")
+                .bind(pid)
+                .execute(&pool)
+                .await;
+            if let Err(e) = res {
+                warn!("cannot cancel the query of backend {}: {}", pid, e);
+            }
+        });
+    }
+}
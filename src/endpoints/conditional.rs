@@ -0,0 +1,221 @@
+use axum::{
+    body::{Body, Bytes, to_bytes},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::types::chrono::{DateTime, NaiveDateTime, Utc};
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Validators of a response, compared with `If-None-Match` and
+/// `If-Modified-Since`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+fn quoted_hash(data: &[u8]) -> String {
+    // 128 bits are plenty to tell representations apart
+    format!("\"{}\"", hex::encode(&Sha256::digest(data)[..16]))
+}
+
+fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    let value = value.as_str()?;
+
+    if let Ok(t) = DateTime::parse_from_rfc3339(value) {
+        return Some(t.with_timezone(&Utc));
+    }
+    // timestamp without time zone, taken as UTC
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f")
+        .ok()
+        .map(|t| t.and_utc())
+}
+
+impl Validators {
+    /// Validators supplied by the SQL through the declared columns, so that
+    /// a 304 can be answered before the rows are converted. `column` reads
+    /// a column of a row, a column missing from the result gives no
+    /// validator and the body hash is used instead.
+    pub fn from_rows<R>(
+        rows: &[R],
+        column: impl Fn(&R, &str) -> Option<Value>,
+        etag_column: Option<&str>,
+        last_modified_column: Option<&str>,
+    ) -> Validators {
+        let values = |name: &str| {
+            rows.iter()
+                .map(|r| column(r, name))
+                .collect::<Option<Vec<_>>>()
+        };
+
+        let etag = etag_column
+            .and_then(values)
+            .map(|versions| quoted_hash(Value::Array(versions).to_string().as_bytes()));
+        let last_modified = last_modified_column
+            .and_then(values)
+            .and_then(|values| values.iter().filter_map(parse_timestamp).max());
+
+        Validators {
+            etag,
+            last_modified,
+        }
+    }
+
+    fn from_headers(headers: &HeaderMap) -> Validators {
+        Validators {
+            etag: headers
+                .get(header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            last_modified: headers
+                .get(header::LAST_MODIFIED)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+                .map(|t| t.with_timezone(&Utc)),
+        }
+    }
+
+    /// `If-None-Match` takes precedence over `If-Modified-Since`.
+    pub fn not_modified(&self, request: &HeaderMap) -> bool {
+        if let Some(if_none_match) = request.get(header::IF_NONE_MATCH) {
+            let Some(etag) = &self.etag else {
+                return false;
+            };
+            return if_none_match.to_str().is_ok_and(|v| {
+                v.split(',')
+                    .map(|t| t.trim().trim_start_matches("W/"))
+                    .any(|t| t == "*" || t == etag)
+            });
+        }
+
+        let if_modified_since = request
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+        match (if_modified_since, self.last_modified) {
+            // the header only has a precision of seconds
+            (Some(since), Some(modified)) => modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    pub fn insert_into(&self, headers: &mut HeaderMap) {
+        if let Some(etag) = self
+            .etag
+            .as_ref()
+            .and_then(|e| HeaderValue::from_str(e).ok())
+        {
+            headers.insert(header::ETAG, etag);
+        }
+        if let Some(modified) = self.last_modified {
+            let value = modified.format(HTTP_DATE).to_string();
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(header::LAST_MODIFIED, value);
+            }
+        }
+    }
+
+    pub fn not_modified_response(&self) -> Response {
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        self.insert_into(response.headers_mut());
+
+        response
+    }
+}
+
+/// Gives successful GET responses a strong ETag computed from the body,
+/// unless the handler already set validators, and answers 304 when the
+/// client holds the same representation.
+pub async fn conditional_middleware(request: Request, next: Next) -> Response {
+    let request_headers = request.headers().clone();
+    let response = next.run(request).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, mut body) = response.into_parts();
+    let mut validators = Validators::from_headers(&parts.headers);
+
+    if validators.etag.is_none() && validators.last_modified.is_none() {
+        let bytes: Bytes = match to_bytes(body, usize::MAX).await {
+            Ok(b) => b,
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+        };
+        validators.etag = Some(quoted_hash(&bytes));
+        validators.insert_into(&mut parts.headers);
+        body = Body::from(bytes);
+    }
+
+    if validators.not_modified(&request_headers) {
+        let mut response = validators.not_modified_response();
+        if let Some(cache_control) = parts.headers.get(header::CACHE_CONTROL) {
+            response
+                .headers_mut()
+                .insert(header::CACHE_CONTROL, cache_control.clone());
+        }
+        return response;
+    }
+
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_validators() {
+        let rows = json!([
+            {"id": 1, "version": 3, "updated_at": "2025-01-02T10:00:00+00:00"},
+            {"id": 2, "version": 5, "updated_at": "2025-01-03 10:00:00"},
+        ]);
+        let column = |r: &Value, c: &str| r.get(c).cloned();
+        let validators = Validators::from_rows(
+            rows.as_array().unwrap(),
+            column,
+            Some("version"),
+            Some("updated_at"),
+        );
+
+        let mut request = HeaderMap::new();
+        request.insert(
+            header::IF_MODIFIED_SINCE,
+            "Fri, 03 Jan 2025 10:00:00 GMT".parse().unwrap(),
+        );
+        assert!(validators.not_modified(&request));
+
+        request.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_str(validators.etag.as_ref().unwrap()).unwrap(),
+        );
+        assert!(validators.not_modified(&request));
+
+        request.insert(header::IF_NONE_MATCH, "\"other\"".parse().unwrap());
+        assert!(!validators.not_modified(&request));
+
+        let mut headers = HeaderMap::new();
+        validators.insert_into(&mut headers);
+        assert_eq!(
+            headers[header::LAST_MODIFIED],
+            "Fri, 03 Jan 2025 10:00:00 GMT"
+        );
+        assert_eq!(Validators::from_headers(&headers), validators);
+
+        // e.g. left out by ?select=, a constant ETag would never change
+        let projected = json!([{"id": 1}, {"id": 2}]);
+        let validators = Validators::from_rows(
+            projected.as_array().unwrap(),
+            column,
+            Some("version"),
+            Some("updated_at"),
+        );
+        assert_eq!(validators, Validators::default());
+    }
+}
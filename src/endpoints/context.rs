@@ -0,0 +1,175 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::HeaderMap;
+use axum::http::request::Parts;
+use serde_json::{Map, Value};
+
+use crate::auth::Identity;
+
+/// Parameter namespaces followed by a dotted path, e.g. `:_claims.sub`.
+pub const CONTEXT_NAMESPACES: [&str; 2] = ["_claims", "_header"];
+const IP_PARAM: &str = "_ip";
+const API_KEY_PARAM: &str = "_api_key";
+const PAYLOAD_PARAM: &str = "_payload";
+
+/// Parameters resolved from the request itself instead of the query string
+/// or body, so the SQL can rely on them.
+pub fn is_context_param(name: &str) -> bool {
+    name == IP_PARAM
+        || name == API_KEY_PARAM
+        || name == PAYLOAD_PARAM
+        || CONTEXT_NAMESPACES
+            .iter()
+            .any(|ns| name.strip_prefix(ns).is_some_and(|p| p.starts_with('.')))
+}
+
+/// What is known about the caller beside the parameters it sent.
+#[derive(Debug, Clone, Default)]
+pub struct RequestContext {
+    pub identity: Option<Identity>,
+    pub headers: HeaderMap,
+    pub ip: Option<IpAddr>,
+    /// payload of the notification a streaming endpoint runs its SQL for
+    pub payload: Option<String>,
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestContext {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(RequestContext {
+            identity: parts.extensions.get::<Identity>().cloned(),
+            headers: parts.headers.clone(),
+            ip: parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|c| c.0.ip()),
+            payload: None,
+        })
+    }
+}
+
+impl RequestContext {
+    fn resolve(&self, name: &str) -> Value {
+        if name == IP_PARAM {
+            return self
+                .ip
+                .map_or(Value::Null, |ip| Value::String(ip.to_string()));
+        }
+
+        if name == PAYLOAD_PARAM {
+            return self.payload.clone().map_or(Value::Null, Value::String);
+        }
+
+        if name == API_KEY_PARAM {
+            return self
+                .identity
+                .as_ref()
+                .and_then(|i| i.api_key.as_ref())
+                .map_or(Value::Null, |k| Value::String(k.name.clone()));
+        }
+
+        if let Some(path) = name.strip_prefix("_claims.") {
+            let mut value = self.identity.as_ref().map(|i| &i.claims);
+            for key in path.split('.') {
+                value = value.and_then(|v| v.get(key));
+            }
+            return value.cloned().unwrap_or(Value::Null);
+        }
+
+        if let Some(header) = name.strip_prefix("_header.") {
+            return self
+                .headers
+                .get(header.replace('_', "-"))
+                .and_then(|v| v.to_str().ok())
+                .map_or(Value::Null, |v| Value::String(v.to_string()));
+        }
+
+        Value::Null
+    }
+
+    /// Values of the context parameters among `names`. Unknown claims and
+    /// missing headers resolve to NULL rather than failing the request.
+    pub fn resolve_params(&self, names: &[String]) -> Map<String, Value> {
+        names
+            .iter()
+            .filter(|n| is_context_param(n))
+            .map(|n| (n.clone(), self.resolve(n)))
+            .collect()
+    }
+}
+
+/// Looks a parameter up in the context values first, so that a client can
+/// never override a context parameter through the query string or body.
+pub fn lookup_param<'a>(
+    name: &String,
+    params: &'a Map<String, Value>,
+    context: &'a Map<String, Value>,
+) -> Option<&'a Value> {
+    if is_context_param(name) {
+        return context.get(name);
+    }
+
+    params.get(name)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use crate::auth::api_key::ApiKey;
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_params() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", "acme".parse().unwrap());
+        let context = RequestContext {
+            identity: Some(Identity {
+                claims: json!({"sub": "alice", "org": {"id": 7}}),
+                api_key: Some(Arc::new(ApiKey {
+                    name: "reporting".to_string(),
+                    ..Default::default()
+                })),
+            }),
+            headers,
+            ip: Some("10.0.0.1".parse().unwrap()),
+            payload: None,
+        };
+
+        let names: Vec<String> = [
+            "_claims.sub",
+            "_claims.org.id",
+            "_claims.missing",
+            "_header.x_tenant",
+            "_ip",
+            "_api_key",
+            "sub",
+        ]
+        .iter()
+        .map(|n| n.to_string())
+        .collect();
+        let resolved = context.resolve_params(&names);
+
+        assert_eq!(resolved["_claims.sub"], json!("alice"));
+        assert_eq!(resolved["_claims.org.id"], json!(7));
+        assert_eq!(resolved["_claims.missing"], Value::Null);
+        assert_eq!(resolved["_header.x_tenant"], json!("acme"));
+        assert_eq!(resolved["_ip"], json!("10.0.0.1"));
+        assert_eq!(resolved["_api_key"], json!("reporting"));
+        assert!(!resolved.contains_key("sub"));
+
+        let params: Map<String, Value> = [("_ip".to_string(), json!("127.0.0.1"))]
+            .into_iter()
+            .collect();
+        assert_eq!(
+            lookup_param(&"_ip".to_string(), &params, &resolved),
+            Some(&json!("10.0.0.1"))
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::auth::AuthMode;
+use crate::limits::rate_limit::RateLimit;
+
+/// Runtime view of the yml declaration placed in the leading comment of an
+/// endpoint file. Only the keys the server acts on are modeled here, the
+/// full document is handed to rstmytype for the OpenAPI output.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Declaration {
+    pub allowlist: Allowlist,
+    pub response: Response,
+    /// Enables `?order=` and `?field=op.value` on the declared response fields
+    pub filtering: bool,
+    /// Results of other GET endpoints merged into each row under the key
+    pub embed: BTreeMap<String, Embed>,
+    /// Overrides the `--auth-default` mode
+    pub auth: Option<AuthMode>,
+    #[serde(flatten)]
+    pub authorization: Authorization,
+    /// Requests per caller, e.g. `10/s burst 20`
+    pub rate_limit: Option<RateLimit>,
+    /// Queries running at once, capped by `--max-concurrency`
+    pub max_concurrency: Option<NonZeroUsize>,
+    /// Requests waiting for a slot, unbounded when unset
+    pub max_queue: Option<usize>,
+    /// How long a request waits for a slot, e.g. `5s`
+    #[serde(with = "humantime_serde")]
+    pub queue_timeout: Option<Duration>,
+    /// Statement timeout of the endpoint SQL, e.g. `30s`
+    #[serde(with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+    /// In-memory caching of the GET responses
+    pub cache: Option<CacheConfig>,
+    /// Column whose values make up the ETag instead of a hash of the body
+    pub etag_column: Option<String>,
+    /// Timestamp column whose latest value is sent as `Last-Modified`
+    pub last_modified_column: Option<String>,
+    /// Channels whose notifications evict the cached responses
+    pub invalidate_on: Vec<String>,
+    /// Channels notified after the SQL succeeded, with the url path as payload
+    pub invalidates: Vec<String>,
+    /// Channel an SSE endpoint streams the notifications of
+    pub channel: Option<String>,
+    /// Interval of the SSE keep-alive comments, overrides `--sse-heartbeat`
+    #[serde(with = "humantime_serde")]
+    pub heartbeat: Option<Duration>,
+    /// Named datasource the SQL runs on, overrides the project one. Embedded
+    /// endpoints must run on the datasource of the embedding one
+    pub datasource: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheConfig {
+    #[serde(with = "humantime_serde")]
+    pub ttl: Duration,
+    /// Claims (`_claims.sub`), headers (`_header.accept_language`) or query
+    /// parameters the response depends on beside the bound parameters
+    #[serde(default)]
+    pub vary: Vec<String>,
+}
+
+/// Who may call an endpoint, unset values fall back to the project defaults.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct Authorization {
+    /// Values of the role claim (see `--rls-role-claim`) allowed to call
+    pub allow_roles: Option<Vec<String>>,
+    /// Dotted claim path -> required value, or list of accepted values
+    pub require_claims: Option<BTreeMap<String, Value>>,
+}
+
+impl Authorization {
+    pub fn or(&self, default: &Authorization) -> Authorization {
+        Authorization {
+            allow_roles: self
+                .allow_roles
+                .clone()
+                .or_else(|| default.allow_roles.clone()),
+            require_claims: self
+                .require_claims
+                .clone()
+                .or_else(|| default.require_claims.clone()),
+        }
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.allow_roles.is_some() || self.require_claims.is_some()
+    }
+}
+
+/// What an endpoint requires from its callers once the project defaults and
+/// `--auth-default` are applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Access {
+    pub mode: AuthMode,
+    pub role_claim: String,
+    pub authorization: Authorization,
+}
+
+impl Access {
+    /// Whether every caller allowed here is allowed to call `other` too, so
+    /// that `other` can be embedded.
+    pub fn covers(&self, other: &Access) -> bool {
+        let mine = &self.authorization;
+        let theirs = &other.authorization;
+
+        let mode = other.mode != AuthMode::Required || self.mode == AuthMode::Required;
+        let roles = match (&mine.allow_roles, &theirs.allow_roles) {
+            (_, None) => true,
+            (Some(mine), Some(theirs)) => {
+                self.role_claim == other.role_claim && mine.iter().all(|r| theirs.contains(r))
+            }
+            (None, Some(_)) => false,
+        };
+        let claims = theirs.require_claims.iter().flatten().all(|(path, value)| {
+            mine.require_claims.as_ref().and_then(|c| c.get(path)) == Some(value)
+        });
+
+        mode && roles && claims
+    }
+
+    /// Whether callers may be authenticated, whose responses must not be
+    /// shared with other callers.
+    pub fn authenticates(&self) -> bool {
+        self.mode != AuthMode::None || self.authorization.is_restricted()
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Embed {
+    /// Url path of the embedded GET endpoint, e.g. `/shop/order_lines`
+    pub endpoint: String,
+    /// Embedded endpoint parameter -> column of the embedding row
+    pub params: BTreeMap<String, String>,
+}
+
+/// Parameters accepted from the query string and the body.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Allowlist {
+    pub query: Vec<Param>,
+    pub body: Vec<Param>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Param {
+    pub field: String,
+    /// Redacted from the logs
+    pub sensitive: bool,
+}
+
+impl Allowlist {
+    pub fn sensitive(&self) -> Vec<String> {
+        self.query
+            .iter()
+            .chain(&self.body)
+            .filter(|p| p.sensitive)
+            .map(|p| p.field.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Response {
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct Field {
+    pub field: String,
+    #[serde(rename = "type")]
+    pub field_type: String,
+}
+
+#[derive(Deserialize)]
+struct DeclarationFile {
+    #[serde(default)]
+    declaration: Declaration,
+}
+
+impl Declaration {
+    /// Fails on an invalid declaration, whose endpoint must not be served
+    /// without the restrictions it may declare.
+    pub fn parse(schema: &str) -> Result<Declaration, String> {
+        if schema.trim().is_empty() {
+            return Ok(Declaration::default());
+        }
+
+        serde_yaml_ng::from_str::<DeclarationFile>(schema)
+            .map(|file| file.declaration)
+            .map_err(|e| format!("invalid declaration: {}", e))
+    }
+
+    /// Authorization rules of the endpoint, an explicit `auth: none` drops
+    /// the ones of the project.
+    pub fn authorization(&self, project: &ProjectConfig) -> Authorization {
+        if self.auth == Some(AuthMode::None) {
+            return self.authorization.clone();
+        }
+
+        self.authorization.or(&project.authorization)
+    }
+
+    /// Datasource of the endpoint, the default one when `None`.
+    pub fn datasource<'a>(&'a self, project: &'a ProjectConfig) -> Option<&'a str> {
+        self.datasource.as_deref().or(project.datasource.as_deref())
+    }
+
+    /// Fails when the declaration disables authentication but restricts
+    /// the callers.
+    pub fn access(
+        &self,
+        project: &ProjectConfig,
+        args: &crate::args::types::Args,
+    ) -> Result<Access, String> {
+        let authorization = self.authorization(project);
+        let mode = match self.auth {
+            Some(AuthMode::None) if authorization.is_restricted() => {
+                return Err("auth: none conflicts with allow_roles and require_claims".to_string());
+            }
+            // authorization rules imply that credentials are required
+            _ if authorization.is_restricted() => AuthMode::Required,
+            declared => declared.unwrap_or(args.auth_default),
+        };
+
+        Ok(Access {
+            mode,
+            role_claim: project.role_claim(args).to_string(),
+            authorization,
+        })
+    }
+}
+
+/// Project wide settings read from `project.yml` in the project directory.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProjectConfig {
+    pub rls: RlsConfig,
+    /// Default authorization of the project endpoints
+    #[serde(flatten)]
+    pub authorization: Authorization,
+    /// Default rate limit of the project endpoints
+    pub rate_limit: Option<RateLimit>,
+    /// Datasource of the project endpoints, declared in `datasources.yml`,
+    /// the `--db-uri` database when unset
+    pub datasource: Option<String>,
+}
+
+impl ProjectConfig {
+    pub fn role_claim<'a>(&'a self, args: &'a crate::args::types::Args) -> &'a str {
+        self.rls.role_claim.as_deref().unwrap_or(&args.rls_role_claim)
+    }
+}
+
+/// Row-level security settings, unset values fall back to the `--rls-*`
+/// arguments.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default)]
+pub struct RlsConfig {
+    pub enabled: Option<bool>,
+    pub role_claim: Option<String>,
+    pub anon_role: Option<String>,
+    pub claims_setting: Option<String>,
+}
+
+pub const PROJECT_CONFIG_FILE: &str = "project.yml";
+
+impl ProjectConfig {
+    /// Defaults without a config file, fails on a file that cannot be read
+    /// or parsed, whose project must not be served without its settings.
+    pub fn load(project_dir: &Path) -> Result<ProjectConfig, String> {
+        let path = project_dir.join(PROJECT_CONFIG_FILE);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(ProjectConfig::default());
+            }
+            Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+        };
+
+        serde_yaml_ng::from_str::<ProjectConfig>(&content)
+            .map_err(|e| format!("cannot parse {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::types::Args;
+
+    #[test]
+    fn test_access() {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl"]);
+        let project = ProjectConfig {
+            authorization: Authorization {
+                allow_roles: Some(vec!["admin".to_string()]),
+                require_claims: None,
+            },
+            ..Default::default()
+        };
+
+        let inherited = Declaration::default().access(&project, &args).unwrap();
+        assert_eq!(inherited.mode, AuthMode::Required);
+        assert_eq!(inherited.authorization, project.authorization);
+
+        // an explicit auth: none wins over the project rules
+        let public = Declaration {
+            auth: Some(AuthMode::None),
+            ..Default::default()
+        };
+        let access = public.access(&project, &args).unwrap();
+        assert_eq!(access.mode, AuthMode::None);
+        assert!(!access.authorization.is_restricted());
+
+        let conflicting = Declaration {
+            authorization: project.authorization.clone(),
+            ..public
+        };
+        assert!(conflicting.access(&project, &args).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let declaration =
+            Declaration::parse("declaration:\n  auth: required\n  timeout: 30s\n").unwrap();
+        assert_eq!(declaration.auth, Some(AuthMode::Required));
+        assert_eq!(declaration.timeout, Some(Duration::from_secs(30)));
+
+        // an invalid value must not drop the auth requirement
+        assert!(Declaration::parse("declaration:\n  auth: required\n  timeout: 30\n").is_err());
+        assert!(Declaration::parse("").unwrap().auth.is_none());
+        // no query would ever get a slot
+        assert!(Declaration::parse("declaration:\n  max_concurrency: 0\n").is_err());
+    }
+
+    #[test]
+    fn test_load_project() {
+        assert!(ProjectConfig::load(Path::new("./test_dsl/test")).is_ok());
+
+        let dir = std::env::temp_dir().join(format!("rstsql-project-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(ProjectConfig::load(&dir).is_ok());
+
+        // an invalid value must not drop the project rules
+        std::fs::write(
+            dir.join(PROJECT_CONFIG_FILE),
+            "allow_roles: [admin]\nrls:\n  enabled: maybe\n",
+        )
+        .unwrap();
+        let loaded = ProjectConfig::load(&dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(loaded.is_err());
+    }
+}
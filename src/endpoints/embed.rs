@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+
+use itertools::Itertools;
+use log::warn;
+use serde_json::Value;
+use sqlx::PgConnection;
+
+use crate::db::DEFAULT_DATASOURCE;
+use crate::endpoints::context::{RequestContext, lookup_param};
+use crate::endpoints::declaration::Embed;
+use crate::endpoints::parser::{Endpoint, EndpointIndex};
+use crate::endpoints::sql_utils::json_to_params::bind_json_to_query;
+use crate::endpoints::sql_utils::preprocess::{as_subquery, quote_ident, replace_named_params};
+use crate::endpoints::sql_utils::row_to_json::row_to_json;
+
+const ORD_COLUMN: &str = "_rstsql_ord";
+
+/// Merges the result of another GET endpoint into each row of the embedding
+/// endpoint. All the rows are resolved with a single query: the distinct key
+/// values are passed as arrays, unnested and lateral joined with the embedded
+/// SQL, so the embedded endpoint is executed once per embed and not per row.
+///
+/// Keys are bound as text, exactly like query string values of a direct GET
+/// call, so the embedded SQL does not need to know it is being embedded.
+/// Embeds declared by the embedded endpoint itself are not resolved, and
+/// neither are its auth layer nor its project RLS settings: an endpoint can
+/// only embed the ones its callers may call.
+#[derive(Clone)]
+pub struct EmbedHandler {
+    name: String,
+    sql: String,
+    /// columns of the embedding row, in the order of the key arrays
+    key_columns: Vec<String>,
+    /// parameters of the embedded SQL taken from the embedding request
+    params_order: Vec<String>,
+}
+
+fn key_to_text(value: Option<&Value>) -> Option<String> {
+    match value {
+        None | Some(Value::Null) => None,
+        Some(Value::String(s)) => Some(s.clone()),
+        Some(v) => Some(v.to_string()),
+    }
+}
+
+impl EmbedHandler {
+    pub fn new(
+        name: &str,
+        embed: &Embed,
+        embedding: &Endpoint,
+        index: &EndpointIndex,
+        args: &crate::args::types::Args,
+    ) -> Option<EmbedHandler> {
+        let Some(target) = index.get_endpoints.get(&embed.endpoint) else {
+            warn!(
+                "Skipping embed {}: GET endpoint {} does not exist",
+                name, embed.endpoint
+            );
+            return None;
+        };
+
+        let access = |e: &Endpoint| e.declaration.access(index.project(e), args).ok();
+        let covered = match (access(embedding), access(target)) {
+            (Some(embedding), Some(target)) => embedding.covers(&target),
+            _ => false,
+        };
+        if !covered {
+            warn!(
+                "Skipping embed {}: {} allows fewer callers than {}",
+                name, embed.endpoint, embedding.url_path
+            );
+            return None;
+        }
+
+        // the embedded SQL runs on the connection of the embedding endpoint
+        let datasource = |e: &Endpoint| {
+            e.declaration
+                .datasource(index.project(e))
+                .unwrap_or(DEFAULT_DATASOURCE)
+                .to_string()
+        };
+        let (source, target_source) = (datasource(embedding), datasource(target));
+        if source != target_source {
+            warn!(
+                "Skipping embed {}: {} runs on datasource {}, {} on {}",
+                name, embed.endpoint, target_source, embedding.url_path, source
+            );
+            return None;
+        }
+
+        let key_params: Vec<&String> = embed.params.keys().collect();
+        let mut params_order = Vec::new();
+        let inner = replace_named_params(as_subquery(&target.file_content), |param| {
+            if let Some(i) = key_params.iter().position(|k| *k == param) {
+                return format!("\"_rstsql_keys\".\"k{}\"", i);
+            }
+
+            params_order.push(param.to_string());
+            format!("${}", params_order.len())
+        });
+
+        let sql = if key_params.is_empty() {
+            inner
+        } else {
+            let arrays = (0..key_params.len())
+                .map(|i| format!("${}::text[]", params_order.len() + 1 + i))
+                .join(", ");
+            let columns = (0..key_params.len())
+                .map(|i| format!("\"k{}\"", i))
+                .join(", ");
+
+            format!(
+                "SELECT \"_rstsql_keys\".\"ord\" AS {}, \"_rstsql_embed\".* \
+                 FROM unnest({}) WITH ORDINALITY AS \"_rstsql_keys\"({}, \"ord\") \
+                 CROSS JOIN LATERAL (\n{}\n) AS \"_rstsql_embed\"",
+                quote_ident(ORD_COLUMN),
+                arrays,
+                columns,
+                inner
+            )
+        };
+
+        Some(EmbedHandler {
+            name: name.to_string(),
+            sql,
+            key_columns: embed.params.values().cloned().collect(),
+            params_order,
+        })
+    }
+
+    /// Parameters of the embedded SQL taken from the embedding request.
+    pub fn params_order(&self) -> &[String] {
+        &self.params_order
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Columns of the embedding rows the embed is computed from.
+    pub fn source_columns(&self) -> &[String] {
+        &self.key_columns
+    }
+
+    /// Runs the embedded endpoint for all `rows` and stores its result in
+    /// each row under the embed name.
+    pub async fn apply(
+        &self,
+        rows: &mut [Value],
+        params: &serde_json::Map<String, Value>,
+        context: &RequestContext,
+        conn: &mut PgConnection,
+    ) -> anyhow::Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let context_params = context.resolve_params(&self.params_order);
+        let args: Vec<(&String, Option<&Value>)> = self
+            .params_order
+            .iter()
+            .map(|k| (k, lookup_param(k, params, &context_params)))
+            .collect();
+
+        // distinct key tuples, each row points to the index of its tuple
+        let mut keys: Vec<Vec<Option<String>>> = Vec::new();
+        let mut key_index: HashMap<Vec<Option<String>>, usize> = HashMap::new();
+        let mut row_keys = Vec::with_capacity(rows.len());
+        for row in rows.iter() {
+            let key: Vec<Option<String>> = self
+                .key_columns
+                .iter()
+                .map(|c| key_to_text(row.get(c)))
+                .collect();
+            let index = *key_index.entry(key.clone()).or_insert_with(|| {
+                keys.push(key);
+                keys.len() - 1
+            });
+            row_keys.push(index);
+        }
+
+        let mut query = bind_json_to_query(sqlx::query(&self.sql), &args)?;
+        for i in 0..self.key_columns.len() {
+            let array: Vec<Option<String>> = keys.iter().map(|k| k[i].clone()).collect();
+            query = query.bind(array);
+        }
+
+        let result = query.fetch_all(conn).await?;
+
+        let mut groups: Vec<Vec<Value>> = vec![Vec::new(); keys.len()];
+        for row in result.iter() {
+            let mut value = row_to_json(row);
+            if self.key_columns.is_empty() {
+                groups[0].push(value);
+                continue;
+            }
+
+            let ord = value
+                .as_object_mut()
+                .and_then(|o| o.remove(ORD_COLUMN))
+                .and_then(|v| v.as_u64())
+                .unwrap_or(0) as usize;
+            if let Some(group) = ord.checked_sub(1).and_then(|i| groups.get_mut(i)) {
+                group.push(value);
+            }
+        }
+
+        for (row, index) in rows.iter_mut().zip(row_keys) {
+            if let Some(obj) = row.as_object_mut() {
+                obj.insert(self.name.clone(), Value::Array(groups[index].clone()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::types::Args;
+    use crate::endpoints::declaration::{Authorization, Declaration};
+    use crate::endpoints::parser::{EndpointCollections, EndpointMethod, Project};
+
+    fn endpoint(url_path: &str, sql: &str, declaration: Declaration) -> Endpoint {
+        Endpoint {
+            tag: "shop".to_string(),
+            method: EndpointMethod::GET,
+            url_path: url_path.to_string(),
+            file_path: format!("shop/GET{}.sql", url_path.trim_start_matches("/shop")),
+            file_content: sql.to_string(),
+            schema: "".to_string(),
+            declaration,
+        }
+    }
+
+    fn collection(endpoints: Vec<Endpoint>) -> EndpointCollections {
+        EndpointCollections {
+            projects: vec![Project {
+                project_name: "shop".to_string(),
+                endpoints,
+                config: Default::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn test_batch_sql() {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl"]);
+        let collection = collection(vec![
+            endpoint(
+                "/shop/orders",
+                "SELECT * FROM orders",
+                Declaration::default(),
+            ),
+            endpoint(
+                "/shop/order_lines",
+                "SELECT * FROM lines WHERE order_id = :order_id::int AND kind = :kind;\n",
+                Declaration::default(),
+            ),
+        ]);
+        let index = EndpointIndex::new(&collection);
+        let orders = &collection.projects[0].endpoints[0];
+        let embed = Embed {
+            endpoint: "/shop/order_lines".to_string(),
+            params: [("order_id".to_string(), "id".to_string())].into(),
+        };
+
+        let handler = EmbedHandler::new("lines", &embed, orders, &index, &args).unwrap();
+        assert_eq!(
+            handler.sql,
+            "SELECT \"_rstsql_keys\".\"ord\" AS \"_rstsql_ord\", \"_rstsql_embed\".* \
+             FROM unnest($2::text[]) WITH ORDINALITY AS \"_rstsql_keys\"(\"k0\", \"ord\") \
+             CROSS JOIN LATERAL (\nSELECT * FROM lines WHERE order_id = \"_rstsql_keys\".\"k0\"::int AND kind = $1\n) AS \"_rstsql_embed\""
+        );
+        assert_eq!(handler.source_columns(), ["id".to_string()]);
+        assert_eq!(handler.params_order, vec!["kind".to_string()]);
+
+        let missing = Embed {
+            endpoint: "/shop/missing".to_string(),
+            params: Default::default(),
+        };
+        assert!(EmbedHandler::new("missing", &missing, orders, &index, &args).is_none());
+    }
+
+    #[test]
+    fn test_restricted_target() {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl"]);
+        let admins = |roles: &[&str]| Declaration {
+            authorization: Authorization {
+                allow_roles: Some(roles.iter().map(|r| r.to_string()).collect()),
+                require_claims: None,
+            },
+            ..Default::default()
+        };
+        let collection = collection(vec![
+            endpoint("/shop/public", "SELECT 1", Declaration::default()),
+            endpoint("/shop/staff", "SELECT 2", admins(&["admin", "clerk"])),
+            endpoint("/shop/admin", "SELECT 3", admins(&["admin"])),
+        ]);
+        let index = EndpointIndex::new(&collection);
+        let [public, staff, admin] = [0, 1, 2].map(|i| &collection.projects[0].endpoints[i]);
+        let embed = |path: &str| Embed {
+            endpoint: path.to_string(),
+            params: Default::default(),
+        };
+
+        assert!(EmbedHandler::new("e", &embed("/shop/staff"), public, &index, &args).is_none());
+        assert!(EmbedHandler::new("e", &embed("/shop/admin"), staff, &index, &args).is_none());
+        assert!(EmbedHandler::new("e", &embed("/shop/staff"), admin, &index, &args).is_some());
+        assert!(EmbedHandler::new("e", &embed("/shop/public"), staff, &index, &args).is_some());
+    }
+
+    #[test]
+    fn test_other_datasource() {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl"]);
+        let on = |datasource: &str| Declaration {
+            datasource: Some(datasource.to_string()),
+            ..Default::default()
+        };
+        let collection = collection(vec![
+            endpoint("/shop/orders", "SELECT 1", Declaration::default()),
+            endpoint("/shop/stock", "SELECT 2", on("warehouse")),
+            endpoint("/shop/prices", "SELECT 3", on(DEFAULT_DATASOURCE)),
+        ]);
+        let index = EndpointIndex::new(&collection);
+        let orders = &collection.projects[0].endpoints[0];
+        let embed = |path: &str| Embed {
+            endpoint: path.to_string(),
+            params: Default::default(),
+        };
+
+        assert!(EmbedHandler::new("e", &embed("/shop/stock"), orders, &index, &args).is_none());
+        assert!(EmbedHandler::new("e", &embed("/shop/prices"), orders, &index, &args).is_some());
+    }
+}
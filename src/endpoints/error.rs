@@ -0,0 +1,37 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+
+use crate::access_log::error_body;
+use crate::limits::concurrency::Overloaded;
+use crate::metrics::Failed;
+
+/// SQLSTATE raised when the statement timeout is hit.
+const QUERY_CANCELED: &str = "57014";
+
+fn is_query_canceled(e: &anyhow::Error) -> bool {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(db)) => db.code().as_deref() == Some(QUERY_CANCELED),
+        _ => false,
+    }
+}
+
+/// Errors the client can act upon get their own status code, everything
+/// else keeps the `{"error": ...}` body with 200.
+pub fn error_status(e: &anyhow::Error) -> StatusCode {
+    if e.downcast_ref::<Overloaded>().is_some() {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else if is_query_canceled(e) {
+        StatusCode::GATEWAY_TIMEOUT
+    } else {
+        StatusCode::OK
+    }
+}
+
+pub fn error_response(e: anyhow::Error) -> Response {
+    let mut response = (error_status(&e), error_body(format!("{}", e))).into_response();
+    response.extensions_mut().insert(Failed);
+
+    response
+}
@@ -1,73 +1,428 @@
+use axum::response::{IntoResponse, Response};
 use serde_json::{Value, json};
 // use uuid;
+use crate::access_log::request_id;
+use crate::endpoints::cancel::CancelOnDrop;
+use crate::endpoints::conditional::Validators;
+use crate::endpoints::context::{RequestContext, is_context_param, lookup_param};
+use crate::endpoints::declaration::{Field, ProjectConfig};
+use crate::endpoints::embed::EmbedHandler;
+use crate::endpoints::parser::{Endpoint, EndpointIndex};
+use crate::endpoints::rls::RowLevelSecurity;
+use crate::endpoints::slow_log::{SlowQueryLog, explain};
 use crate::endpoints::sql_utils::json_to_params::bind_json_to_query;
 use crate::endpoints::sql_utils::preprocess::rewrite_sql_with_named_params;
-use crate::endpoints::sql_utils::row_to_json::row_to_json;
+use crate::endpoints::sql_utils::query_syntax::{
+    FilterValue, ORDER_PARAM, QueryShape, SELECT_PARAM,
+};
+use crate::endpoints::sql_utils::row_to_json::{column_to_json, rows_to_json};
+use crate::limits::concurrency::ConcurrencyLimit;
+use crate::metrics::QueryMetrics;
+use crate::telemetry::{with_span, with_span_sync};
+use opentelemetry::KeyValue;
+use log::warn;
 use serde_json;
-use sqlx::PgPool;
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+use sqlx::{Connection, PgConnection, PgPool, Postgres};
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub struct EndpointHandler {
     sql: String,
     params_order: Vec<String>,
+    response_fields: Vec<Field>,
+    filtering: bool,
+    embeds: Vec<EmbedHandler>,
+    rls: Option<RowLevelSecurity>,
+    concurrency: Option<Arc<ConcurrencyLimit>>,
+    metrics: Option<QueryMetrics>,
+    timeout: Option<Duration>,
+    slow_log: Option<SlowQueryLog>,
+    url_path: String,
+    file_path: String,
+    /// channels notified once the query succeeded
+    invalidates: Vec<String>,
+    etag_column: Option<String>,
+    last_modified_column: Option<String>,
+}
+
+/// Result of the endpoint SQL.
+enum Output {
+    /// validators are only computed for conditional GETs
+    Rows(Value, Validators),
+    NotModified(Validators),
+}
+
+impl Output {
+    fn into_rows(self) -> Value {
+        match self {
+            Output::Rows(rows, _) => rows,
+            Output::NotModified(_) => unreachable!("only answered to conditional GETs"),
+        }
+    }
+}
+
+fn bind_query<'q>(
+    sql: &'q str,
+    args: &'q Vec<(&String, Option<&Value>)>,
+    filter_values: &[FilterValue],
+) -> anyhow::Result<Query<'q, Postgres, PgArguments>> {
+    let mut query = bind_json_to_query(sqlx::query(sql), args)?;
+    for value in filter_values {
+        query = match value.clone() {
+            FilterValue::Text(v) => query.bind(v),
+            FilterValue::List(v) => query.bind(v),
+        };
+    }
+
+    Ok(query)
 }
 
 impl EndpointHandler {
-    pub fn new(file_content: &String) -> EndpointHandler {
-        let (rewritten, order) = rewrite_sql_with_named_params(&file_content);
+    pub fn new(
+        endpoint: &Endpoint,
+        project: &ProjectConfig,
+        args: &crate::args::types::Args,
+        index: &EndpointIndex,
+    ) -> EndpointHandler {
+        let (rewritten, order) = rewrite_sql_with_named_params(&endpoint.file_content);
+        let embeds = endpoint
+            .declaration
+            .embed
+            .iter()
+            .flat_map(|(name, embed)| EmbedHandler::new(name, embed, endpoint, index, args))
+            .collect();
 
         EndpointHandler {
             sql: rewritten,
             params_order: order,
+            response_fields: endpoint.declaration.response.fields.clone(),
+            filtering: endpoint.declaration.filtering,
+            embeds,
+            rls: RowLevelSecurity::new(args, &project.rls),
+            concurrency: None,
+            metrics: None,
+            timeout: endpoint.declaration.timeout.or(args.statement_timeout),
+            slow_log: SlowQueryLog::new(endpoint, args),
+            url_path: endpoint.url_path.clone(),
+            file_path: endpoint.file_path.clone(),
+            invalidates: endpoint.declaration.invalidates.clone(),
+            etag_column: endpoint.declaration.etag_column.clone(),
+            last_modified_column: endpoint.declaration.last_modified_column.clone(),
         }
     }
 
+    pub fn with_concurrency(mut self, limit: ConcurrencyLimit) -> EndpointHandler {
+        self.concurrency = Some(Arc::new(limit));
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: QueryMetrics) -> EndpointHandler {
+        self.metrics = Some(metrics);
+        self
+    }
+
     pub fn param_list_empty(&self) -> bool {
-        return self.params_order.len() == 0;
+        return self.params_order.iter().all(|p| is_context_param(p));
+    }
+
+    /// Parameters of the SQL and of the embeds.
+    pub fn all_params(&self) -> impl Iterator<Item = &String> {
+        self.params_order
+            .iter()
+            .chain(self.embeds.iter().flat_map(|e| e.params_order()))
+    }
+
+    pub fn rls(&self) -> Option<&RowLevelSecurity> {
+        self.rls.as_ref()
+    }
+
+    /// Whether the query string parameter changes the response.
+    pub fn depends_on(&self, param: &str) -> bool {
+        if self.all_params().any(|p| p == param) {
+            return true;
+        }
+        if self.response_fields.is_empty() {
+            return false;
+        }
+
+        param == SELECT_PARAM
+            || (self.filtering
+                && (param == ORDER_PARAM || self.response_fields.iter().any(|f| f.field == param)))
+    }
+
+    fn query_shape(&self, query: &HashMap<String, String>) -> anyhow::Result<QueryShape> {
+        if self.response_fields.is_empty() {
+            return Ok(QueryShape::default());
+        }
+
+        Ok(QueryShape::parse(
+            query,
+            &self.response_fields,
+            &self.params_order,
+            self.filtering,
+        )?)
     }
 
     async fn handle_query(
         &self,
         params: &serde_json::Map<String, Value>,
+        shape: QueryShape,
+        context: &RequestContext,
         pool: PgPool,
-    ) -> anyhow::Result<Value> {
+        conditional: bool,
+    ) -> anyhow::Result<Output> {
+        // held until the query is done, queued requests wait for it
+        let _permit = match &self.concurrency {
+            Some(limit) => Some(limit.acquire().await?),
+            None => None,
+        };
+        let waiting = self.metrics.as_ref().map(|m| m.waiting());
+        let mut conn = CancelOnDrop::acquire(&pool).await?;
+        drop(waiting);
+
+        let start = Instant::now();
+        let out = with_span(
+            "query",
+            vec![
+                KeyValue::new("db.system.name", "postgresql"),
+                KeyValue::new("db.query.text", self.sql.clone()),
+                KeyValue::new("code.file.path", self.file_path.clone()),
+                KeyValue::new("url.template", self.url_path.clone()),
+            ],
+            self.query_in(&mut conn, params, shape, context, conditional),
+        )
+        .await;
+        conn.finish();
+        if let Some(metrics) = &self.metrics {
+            metrics.observe(start.elapsed());
+        }
+        if let Err(e) = &out {
+            warn!(
+                "request {}: {} failed: {}",
+                request_id().unwrap_or_default(),
+                self.file_path,
+                e
+            );
+        }
+
+        out
+    }
+
+    async fn query_in(
+        &self,
+        conn: &mut PgConnection,
+        params: &serde_json::Map<String, Value>,
+        shape: QueryShape,
+        context: &RequestContext,
+        conditional: bool,
+    ) -> anyhow::Result<Output> {
+        if self.rls.is_none() && self.timeout.is_none() {
+            let out = self
+                .run_query(conn, params, shape, context, conditional)
+                .await?;
+            self.notify_invalidated(conn).await?;
+            return Ok(out);
+        }
+
+        // both settings are local to the transaction
+        let mut tx = conn.begin().await?;
+        if let Some(timeout) = self.timeout {
+            sqlx::query("SELECT set_config('statement_timeout', $1, true)")
+                .bind(format!("{}ms", timeout.as_millis()))
+                .execute(&mut *tx)
+                .await?;
+        }
+        if let Some(rls) = &self.rls {
+            rls.apply(&mut tx, context).await?;
+        }
+        let out = self
+            .run_query(&mut tx, params, shape, context, conditional)
+            .await?;
+        // delivered by Postgres once the transaction commits
+        self.notify_invalidated(&mut tx).await?;
+        tx.commit().await?;
+
+        Ok(out)
+    }
+
+    async fn notify_invalidated(&self, conn: &mut PgConnection) -> anyhow::Result<()> {
+        if self.invalidates.is_empty() {
+            return Ok(());
+        }
+
+        sqlx::query("SELECT pg_notify(c, $2) FROM unnest($1::text[]) AS c")
+            .bind(&self.invalidates)
+            .bind(&self.url_path)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn run_query(
+        &self,
+        conn: &mut PgConnection,
+        params: &serde_json::Map<String, Value>,
+        mut shape: QueryShape,
+        context: &RequestContext,
+        conditional: bool,
+    ) -> anyhow::Result<Output> {
+        let requested = shape.selected().to_vec();
+        let embeds: Vec<&EmbedHandler> = self
+            .embeds
+            .iter()
+            .filter(|e| requested.is_empty() || requested.iter().any(|r| r == e.name()))
+            .collect();
+        for embed in &embeds {
+            shape.replace_computed(embed.name(), embed.source_columns());
+        }
+        let etag_column = self.etag_column.as_deref().filter(|_| conditional);
+        let last_modified_column = self.last_modified_column.as_deref().filter(|_| conditional);
+        for column in etag_column.iter().chain(&last_modified_column) {
+            shape.include(column);
+        }
+
+        let context_params = context.resolve_params(&self.params_order);
         let args: Vec<(&String, Option<&Value>)> = self
             .params_order
             .iter()
-            .map(|k| (k, params.get(k)))
+            .map(|k| (k, lookup_param(k, params, &context_params)))
             .collect();
-        let query = sqlx::query(&self.sql);
 
-        let query = bind_json_to_query(query, &args)?;
+        let (sql, filter_values) = if shape.is_empty() {
+            (self.sql.clone(), Vec::new())
+        } else {
+            shape.wrap_sql(&self.sql, self.params_order.len() + 1)
+        };
+        let query = with_span_sync("bind", || bind_query(&sql, &args, &filter_values))?;
 
-        let rows = query.fetch_all(&pool).await?;
+        let start = Instant::now();
+        let rows = with_span("execute", Vec::new(), async {
+            Ok(query.fetch_all(&mut *conn).await?)
+        })
+        .await;
+        let elapsed = start.elapsed();
+        // timeouts and failures are slow too
+        if let Some(slow_log) = &self.slow_log
+            && slow_log.is_slow(elapsed)
+        {
+            // the transaction of a failed query rejects further statements
+            let plan = if rows.is_ok() && slow_log.sample_explain() {
+                let explain_sql = format!("EXPLAIN (ANALYZE, BUFFERS) {}", sql);
+                let plan = match bind_query(&explain_sql, &args, &filter_values) {
+                    Ok(query) => explain(conn, query).await,
+                    Err(e) => Err(e),
+                };
+                plan.inspect_err(|e| warn!("cannot explain {}: {}", self.file_path, e))
+                    .ok()
+            } else {
+                None
+            };
+            slow_log.log(&self.file_path, &sql, &args, elapsed, plan, rows.as_ref().err());
+        }
+        let rows = rows?;
+
+        let validators =
+            Validators::from_rows(&rows, column_to_json, etag_column, last_modified_column);
+        if validators.not_modified(&context.headers) {
+            return Ok(Output::NotModified(validators));
+        }
+
+        let mut out = with_span(
+            "serialize",
+            vec![KeyValue::new("db.response.returned_rows", rows.len() as i64)],
+            rows_to_json(rows),
+        )
+        .await?;
 
-        let mut out = Vec::with_capacity(rows.len());
-        for row in rows.iter() {
-            out.push(row_to_json(row));
+        for embed in embeds {
+            embed.apply(&mut out, params, context, conn).await?;
         }
 
-        Ok(Value::Array(out))
+        if !requested.is_empty() {
+            // drop the columns only selected to compute the embeds and the
+            // validators
+            for row in out.iter_mut().flat_map(|r| r.as_object_mut()) {
+                row.retain(|k, _| requested.contains(k));
+            }
+        }
+
+        Ok(Output::Rows(Value::Array(out), validators))
     }
 
     pub async fn handle_get(
         &self,
         params: &HashMap<String, String>,
+        context: &RequestContext,
         pool: PgPool,
     ) -> anyhow::Result<Value> {
+        let shape = self.query_shape(params)?;
+
         self.handle_query(
             &params.iter().map(|x| (x.0.clone(), json!(x.1))).collect(),
+            shape,
+            context,
             pool,
+            false,
         )
         .await
+        .map(Output::into_rows)
     }
 
-    pub async fn handle_post(&self, params: &Value, pool: PgPool) -> anyhow::Result<Value> {
+    /// GET answered with a 304 before the rows are converted when the
+    /// validators of the declared columns show the client holds them.
+    pub async fn handle_conditional_get(
+        &self,
+        params: &HashMap<String, String>,
+        context: &RequestContext,
+        pool: PgPool,
+    ) -> anyhow::Result<Response> {
+        let shape = self.query_shape(params)?;
+        let conditional = self.etag_column.is_some() || self.last_modified_column.is_some();
+
+        let out = self
+            .handle_query(
+                &params.iter().map(|x| (x.0.clone(), json!(x.1))).collect(),
+                shape,
+                context,
+                pool,
+                conditional,
+            )
+            .await?;
+
+        Ok(match out {
+            Output::Rows(rows, validators) => {
+                let mut response = rows.to_string().into_response();
+                validators.insert_into(response.headers_mut());
+                response
+            }
+            Output::NotModified(validators) => validators.not_modified_response(),
+        })
+    }
+
+    pub async fn handle_post(
+        &self,
+        params: &Value,
+        query: &HashMap<String, String>,
+        context: &RequestContext,
+        pool: PgPool,
+    ) -> anyhow::Result<Value> {
+        let shape = self.query_shape(query)?;
+
         if let Some(v) = params.as_object() {
-            return self.handle_query(v, pool).await;
+            return self
+                .handle_query(v, shape, context, pool, false)
+                .await
+                .map(Output::into_rows);
         }
 
-        return self.handle_query(&serde_json::Map::new(), pool).await;
+        return self
+            .handle_query(&serde_json::Map::new(), shape, context, pool, false)
+            .await
+            .map(Output::into_rows);
     }
 }
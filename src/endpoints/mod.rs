@@ -1,70 +1,309 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use axum::{
     Router,
-    extract::State,
     extract::{Json, Query},
+    handler::Handler,
+    middleware,
+    response::IntoResponse,
     routing::MethodRouter,
 };
 use itertools::Itertools;
-use log::info;
-use serde_json::{Value, json};
+use log::{info, warn};
+use serde_json::Value;
 use sqlx::PgPool;
+use tokio::sync::Semaphore;
 use utoipa_swagger_ui::SwaggerUi;
 use rstmytype::build_open_api;
 
+use crate::auth::policy::Policy;
+use crate::auth::{Authenticator, EndpointAuth, auth_middleware};
+use crate::cache::ResponseCache;
+use crate::cache::invalidation::spawn_invalidation;
+use crate::db::{DEFAULT_DATASOURCE, Datasources};
+use crate::endpoints::cache::EndpointCache;
+use crate::endpoints::conditional::conditional_middleware;
+use crate::endpoints::context::RequestContext;
+use crate::endpoints::error::error_response;
 use crate::endpoints::handler::EndpointHandler;
+use crate::endpoints::openapi::extend_open_api;
 use crate::endpoints::parser::{Endpoint, EndpointMethod};
-use crate::endpoints::parser::{EndpointCollections};
+use crate::endpoints::parser::{EndpointCollections, EndpointIndex};
+use crate::endpoints::sse::SseHandler;
+use crate::endpoints::ws::{WsEndpoint, WsRouter, WsStream};
+use crate::limits::concurrency::ConcurrencyLimit;
+use crate::limits::rate_limit::RateLimiter;
+use crate::limits::{EndpointRateLimit, rate_limit_middleware};
+use crate::health::{DslInfo, Health};
+use crate::metrics::{Metrics, metrics_middleware};
+use crate::notify::NotifyHub;
 
+mod cache;
+mod cancel;
+mod conditional;
+mod context;
+mod declaration;
+mod embed;
+mod error;
 mod handler;
+mod openapi;
 mod parser;
+mod rls;
+mod slow_log;
 mod sql_utils;
+mod sse;
+mod ws;
 
-fn get_route(endpoints: Vec<&Endpoint>) -> MethodRouter<PgPool> {
+/// Everything the routes are built from beside the endpoint itself.
+struct Routes<'a> {
+    args: &'a crate::args::types::Args,
+    authenticator: Arc<Authenticator>,
+    limiter: Arc<RateLimiter>,
+    cache: Arc<ResponseCache>,
+    hub: Arc<NotifyHub>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    datasources: Datasources,
+    /// Slots shared by the endpoints of each datasource
+    datasource_limits: HashMap<String, Arc<Semaphore>>,
+    index: EndpointIndex<'a>,
+}
+
+/// Queries of all the endpoints on the pool running at once, one connection
+/// is always left for the health checks and the cancel requests.
+fn datasource_concurrency(pool: &PgPool) -> usize {
+    (pool.options().get_max_connections() as usize)
+        .saturating_sub(1)
+        .max(1)
+}
+
+impl Routes<'_> {
+    /// Cap of the queries of any endpoint on the pool running at once, one
+    /// slot of the datasource is always left for the other endpoints.
+    fn max_concurrency(&self, pool: &PgPool) -> usize {
+        let datasource = datasource_concurrency(pool);
+        self.args
+            .max_concurrency
+            .unwrap_or_else(|| datasource.saturating_sub(1).max(1))
+            .min(datasource)
+    }
+}
+
+fn get_route(
+    endpoints: Vec<&Endpoint>,
+    routes: &Routes,
+    ws: &mut WsRouter,
+) -> MethodRouter<Datasources> {
     let mut method_router = MethodRouter::new();
 
     for endpoint in endpoints {
-        let endpoint_handler = EndpointHandler::new(&endpoint.file_content);
+        let project = routes.index.project(endpoint);
+        let declaration = &endpoint.declaration;
+        let endpoint_id = format!("{:?} {}", endpoint.method, endpoint.url_path);
+        let access = match declaration.access(project, routes.args) {
+            Ok(access) => access,
+            Err(e) => {
+                warn!("Skipping {}: {}", endpoint_id, e);
+                continue;
+            }
+        };
+        let datasource = declaration.datasource(project);
+        let Some(pool) = routes.datasources.get(datasource).cloned() else {
+            warn!(
+                "Skipping {} because datasource {} is not declared",
+                endpoint_id,
+                datasource.unwrap_or_default()
+            );
+            continue;
+        };
+        if datasource.is_some_and(|d| d != DEFAULT_DATASOURCE)
+            && (!declaration.invalidates.is_empty()
+                || !declaration.invalidate_on.is_empty()
+                || declaration.channel.is_some())
+        {
+            warn!(
+                "{} uses notifications, they are only listened to on the default datasource",
+                endpoint_id
+            );
+        }
+        let allowed_concurrency = routes.max_concurrency(&pool);
+        let max_concurrency = match declaration.max_concurrency.map(|max| max.get()) {
+            Some(max) if max > allowed_concurrency => {
+                warn!(
+                    "max_concurrency of {} lowered to the {} allowed per endpoint",
+                    endpoint.url_path, allowed_concurrency
+                );
+                allowed_concurrency
+            }
+            Some(max) => max,
+            None => allowed_concurrency,
+        };
+        let endpoint_handler =
+            EndpointHandler::new(endpoint, project, routes.args, &routes.index)
+                .with_concurrency(ConcurrencyLimit::new(
+                    max_concurrency,
+                    declaration.max_queue,
+                    declaration.queue_timeout.unwrap_or(routes.args.queue_timeout),
+                ).with_datasource(
+                    routes.datasource_limits[datasource.unwrap_or(DEFAULT_DATASOURCE)].clone(),
+                ))
+                .with_metrics(routes.metrics.query(
+                    &endpoint.tag,
+                    &endpoint.url_path,
+                    datasource.unwrap_or(DEFAULT_DATASOURCE),
+                ));
+        let authenticated = access.authenticates();
+        let policy = Policy::new(
+            &access.role_claim,
+            access.authorization.allow_roles,
+            access.authorization.require_claims,
+        );
+        let endpoint_auth = EndpointAuth::new(
+            routes.authenticator.clone(),
+            Some(access.mode),
+            &endpoint.tag,
+            &endpoint.url_path,
+        )
+        .with_policy(policy);
+        let auth_layer = middleware::from_fn_with_state(endpoint_auth.clone(), auth_middleware);
+        let rate_limit = EndpointRateLimit::new(
+            routes.limiter.clone(),
+            endpoint
+                .declaration
+                .rate_limit
+                .or(project.rate_limit)
+                .or(routes.args.rate_limit),
+            endpoint_id.clone(),
+        );
+        let rate_limit_layer =
+            middleware::from_fn_with_state(rate_limit.clone(), rate_limit_middleware);
+        let endpoint_metrics = routes.metrics.endpoint(
+            &endpoint.tag,
+            &endpoint.url_path,
+            &format!("{:?}", endpoint.method),
+        );
+        let metrics_layer =
+            middleware::from_fn_with_state(endpoint_metrics.clone(), metrics_middleware);
+
+        if endpoint.method != EndpointMethod::SSE {
+            ws.add_endpoint(
+                endpoint_id.clone(),
+                WsEndpoint {
+                    handler: endpoint_handler.clone(),
+                    auth: endpoint_auth.clone(),
+                    rate_limit: rate_limit.clone(),
+                    metrics: endpoint_metrics,
+                    pool: pool.clone(),
+                },
+            );
+        }
 
         if endpoint.method == EndpointMethod::GET {
+            let endpoint_cache = declaration
+                .cache
+                .as_ref()
+                .map(|c| {
+                    EndpointCache::new(routes.cache.clone(), endpoint_id.clone(), c, authenticated)
+                });
             method_router = method_router.get(
-                |State(pool): State<PgPool>, q: Query<HashMap<String, String>>| async move {
-                    let res = endpoint_handler.handle_get(&q.0, pool).await;
+                (|context: RequestContext, q: Query<HashMap<String, String>>| async move {
+                    let res = match &endpoint_cache {
+                        Some(cache) => {
+                            cache
+                                .handle_get(&endpoint_handler, &q.0, &context, pool)
+                                .await
+                        }
+                        None => {
+                            endpoint_handler
+                                .handle_conditional_get(&q.0, &context, pool)
+                                .await
+                        }
+                    };
                     match res {
-                        Ok(r) => r.to_string(),
-                        Err(e) => json!({"error": format!("{}", e)}).to_string(),
+                        Ok(r) => r,
+                        Err(e) => error_response(e),
                     }
-                },
+                })
+                .layer(middleware::from_fn(conditional_middleware))
+                .layer(rate_limit_layer)
+                .layer(auth_layer)
+                .layer(metrics_layer),
             );
         } else if endpoint.method == EndpointMethod::POST {
             if endpoint_handler.param_list_empty() {
-                method_router = method_router.post(|State(pool): State<PgPool>| async move {
-                    let res = endpoint_handler.handle_post(&Value::Null, pool).await;
-                    match res {
-                        Ok(r) => r.to_string(),
-                        Err(e) => json!({"error": format!("{}", e)}).to_string(),
-                    }
-                });
+                method_router = method_router.post(
+                    (|context: RequestContext, q: Query<HashMap<String, String>>| async move {
+                        let res = endpoint_handler
+                            .handle_post(&Value::Null, &q.0, &context, pool)
+                            .await;
+                        match res {
+                            Ok(r) => r.to_string().into_response(),
+                            Err(e) => error_response(e),
+                        }
+                    })
+                    .layer(rate_limit_layer)
+                    .layer(auth_layer)
+                    .layer(metrics_layer),
+                );
             } else {
-                method_router =
-                    method_router.post(|State(pool): State<PgPool>, q: Json<Value>| async move {
-                        let res = endpoint_handler.handle_post(&q.0, pool).await;
+                method_router = method_router.post(
+                    (|context: RequestContext,
+                      q: Query<HashMap<String, String>>,
+                      b: Json<Value>| async move {
+                        let res = endpoint_handler
+                            .handle_post(&b.0, &q.0, &context, pool)
+                            .await;
                         match res {
-                            Ok(r) => r.to_string(),
-                            Err(e) => json!({"error": format!("{}", e)}).to_string(),
+                            Ok(r) => r.to_string().into_response(),
+                            Err(e) => error_response(e),
                         }
-                    });
+                    })
+                    .layer(rate_limit_layer)
+                    .layer(auth_layer)
+                    .layer(metrics_layer),
+                );
             }
-        } else {
+        } else if endpoint.method == EndpointMethod::SSE {
+            let Some(sse_handler) = SseHandler::new(
+                endpoint,
+                endpoint_handler,
+                routes.hub.clone(),
+                routes.health.draining(),
+                routes.args,
+            ) else {
+                continue;
+            };
+            ws.add_stream(
+                endpoint.url_path.clone(),
+                WsStream {
+                    handler: sse_handler.clone(),
+                    auth: endpoint_auth,
+                    rate_limit,
+                    pool: pool.clone(),
+                },
+            );
+            method_router = method_router.get(
+                (|context: RequestContext, q: Query<HashMap<String, String>>| async move {
+                    sse_handler.stream(q.0, context, pool)
+                })
+                .layer(rate_limit_layer)
+                .layer(auth_layer)
+                .layer(metrics_layer),
+            );
         }
     }
 
     method_router
 }
 
-pub fn load_swagger(mut app: Router<PgPool>, collection: &EndpointCollections) -> Router<PgPool> {
-    app = app.merge(SwaggerUi::new("/docs").url("/docs/openapi.json", build_open_api(collection)));
+pub fn load_swagger(
+    mut app: Router<Datasources>,
+    collection: &EndpointCollections,
+    authenticator: &Authenticator,
+) -> Router<Datasources> {
+    let open_api = extend_open_api(build_open_api(collection), collection, authenticator);
+    app = app.merge(SwaggerUi::new("/docs").url("/docs/openapi.json", open_api));
 
     app
 }
@@ -72,26 +311,105 @@ pub fn load_swagger(mut app: Router<PgPool>, collection: &EndpointCollections) -
 
 pub fn load_dsl_endpoints(
     args: &crate::args::types::Args,
-    mut app: Router<PgPool>,
-) -> Router<PgPool> {
+    authenticator: Arc<Authenticator>,
+    metrics: Arc<Metrics>,
+    health: Arc<Health>,
+    datasources: &Datasources,
+    mut app: Router<Datasources>,
+) -> Router<Datasources> {
     info!("Loading DSL endpoints from path: {}", args.dsl_path);
 
     let collection: parser::EndpointCollections =
         parser::EndpointCollections::parse_from_dir(&args.dsl_path);
     info!("Loaded next endpoints collection: {}", collection);
 
+    let index = EndpointIndex::new(&collection);
+    let mut cached_by_channel: HashMap<String, Vec<String>> = HashMap::new();
+    for endpoint in index.get_endpoints.values() {
+        let declaration = &endpoint.declaration;
+        if declaration.cache.is_none() && !declaration.invalidate_on.is_empty() {
+            warn!("{} declares invalidate_on without cache", endpoint.url_path);
+        }
+        if declaration.cache.is_none() {
+            continue;
+        }
+        for channel in &declaration.invalidate_on {
+            cached_by_channel
+                .entry(channel.clone())
+                .or_default()
+                .push(format!("{:?} {}", endpoint.method, endpoint.url_path));
+        }
+    }
+    let channels = cached_by_channel
+        .keys()
+        .chain(
+            collection
+                .projects
+                .iter()
+                .flat_map(|p| &p.endpoints)
+                .filter(|e| e.method == EndpointMethod::SSE)
+                .filter_map(|e| e.declaration.channel.as_ref()),
+        )
+        .chain(&args.ws_channels)
+        .unique()
+        .cloned()
+        .collect();
+
+    let routes = Routes {
+        args,
+        authenticator: authenticator.clone(),
+        limiter: Arc::new(RateLimiter::default()),
+        cache: Arc::new(ResponseCache::new(args.cache_max_bytes)),
+        hub: NotifyHub::start(datasources.default_pool(), channels, health.draining()),
+        metrics,
+        health,
+        datasources: datasources.clone(),
+        datasource_limits: datasources
+            .iter()
+            .map(|(name, pool)| {
+                let permits = Semaphore::new(datasource_concurrency(pool));
+                (name.to_string(), Arc::new(permits))
+            })
+            .collect(),
+        index,
+    };
+
+    spawn_invalidation(routes.cache.clone(), &routes.hub, cached_by_channel);
+
     let flatten_endpoints = collection
         .projects
         .iter()
         .flat_map(|p| &p.endpoints)
         .chunk_by(|e| e.url_path.clone());
 
+    let mut ws = WsRouter::new(routes.hub.clone(), routes.health.draining(), &args.ws_channels);
     for (key, chunk_iter) in &flatten_endpoints {
-        let chunk: Vec<&Endpoint> = chunk_iter.collect();
-        app = app.route(&key, get_route(chunk))
+        let mut chunk: Vec<&Endpoint> = chunk_iter.collect();
+        // SSE endpoints are served on GET too, axum rejects both on a path
+        if chunk.iter().any(|e| e.method == EndpointMethod::GET) {
+            chunk.retain(|e| {
+                let clash = e.method == EndpointMethod::SSE;
+                if clash {
+                    warn!(
+                        "Skipping SSE {} because a GET endpoint has the same path",
+                        key
+                    );
+                }
+                !clash
+            });
+        }
+        app = app.route(&key, get_route(chunk, &routes, &mut ws))
     }
+    app = app.route(&args.ws_path, ws.route(authenticator.clone(), &args.ws_path));
 
-    app = load_swagger(app, &collection);
+    app = load_swagger(app, &collection, &authenticator);
+    routes.health.set_dsl(DslInfo::new(
+        collection
+            .projects
+            .iter()
+            .flat_map(|p| &p.endpoints)
+            .map(|e| (e.file_path.as_str(), e.file_content.as_str())),
+    ));
 
     app
 }
@@ -0,0 +1,129 @@
+use itertools::Itertools;
+use utoipa::openapi::path::{Operation, ParameterBuilder, ParameterIn};
+use utoipa::openapi::schema::{ObjectBuilder, Type};
+use utoipa::openapi::security::{
+    ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityRequirement, SecurityScheme,
+};
+use utoipa::openapi::{Components, OpenApi, Required};
+
+use crate::auth::{AuthMode, Authenticator};
+use crate::endpoints::parser::{Endpoint, EndpointCollections, EndpointMethod};
+use crate::endpoints::sql_utils::query_syntax::SELECT_PARAM;
+
+const BEARER_SCHEME: &str = "bearerAuth";
+const API_KEY_SCHEME: &str = "apiKeyAuth";
+
+fn operation_mut<'a>(api: &'a mut OpenApi, endpoint: &Endpoint) -> Option<&'a mut Operation> {
+    let item = api.paths.paths.get_mut(&endpoint.url_path)?;
+
+    match endpoint.method {
+        EndpointMethod::GET | EndpointMethod::SSE => item.get.as_mut(),
+        EndpointMethod::POST => item.post.as_mut(),
+    }
+}
+
+fn add_select_parameter(operation: &mut Operation, endpoint: &Endpoint) {
+    let fields = endpoint
+        .declaration
+        .response
+        .fields
+        .iter()
+        .map(|f| f.field.as_str())
+        .join(", ");
+
+    let parameter = ParameterBuilder::new()
+        .name(SELECT_PARAM)
+        .parameter_in(ParameterIn::Query)
+        .required(Required::False)
+        .description(Some(format!(
+            "Comma separated subset of the response fields to return: {}",
+            fields
+        )))
+        .schema(Some(ObjectBuilder::new().schema_type(Type::String)))
+        .build();
+
+    operation
+        .parameters
+        .get_or_insert_with(Vec::new)
+        .push(parameter);
+}
+
+fn add_security(operation: &mut Operation, mode: AuthMode, schemes: &[&str], roles: &[String]) {
+    // any one of the schemes is enough, hence one requirement per scheme,
+    // the allowed roles are listed as the scopes of each
+    let mut requirements: Vec<SecurityRequirement> = schemes
+        .iter()
+        .map(|s| SecurityRequirement::new(*s, roles))
+        .collect();
+
+    operation.security = match mode {
+        AuthMode::None => None,
+        AuthMode::Optional => {
+            requirements.push(SecurityRequirement::default());
+            Some(requirements)
+        }
+        AuthMode::Required => Some(requirements),
+    };
+}
+
+/// Documents the runtime features that rstmytype does not know about on top
+/// of the OpenAPI built from the endpoint declarations.
+pub fn extend_open_api(
+    mut api: OpenApi,
+    collection: &EndpointCollections,
+    authenticator: &Authenticator,
+) -> OpenApi {
+    let mut schemes = Vec::new();
+    if authenticator.jwt_enabled() {
+        schemes.push(BEARER_SCHEME);
+        api.components
+            .get_or_insert_with(Components::new)
+            .add_security_scheme(
+                BEARER_SCHEME,
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .build(),
+                ),
+            );
+    }
+    if let Some(header) = authenticator.api_key_header() {
+        schemes.push(API_KEY_SCHEME);
+        api.components
+            .get_or_insert_with(Components::new)
+            .add_security_scheme(
+                API_KEY_SCHEME,
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(header))),
+            );
+    }
+
+    let endpoints = collection
+        .projects
+        .iter()
+        .flat_map(|p| p.endpoints.iter().map(move |e| (e, &p.config)));
+
+    for (endpoint, project) in endpoints {
+        let Some(operation) = operation_mut(&mut api, endpoint) else {
+            continue;
+        };
+
+        if !endpoint.declaration.response.fields.is_empty() {
+            add_select_parameter(operation, endpoint);
+        }
+
+        if !schemes.is_empty() {
+            let authorization = endpoint.declaration.authorization(project);
+            let mode = if authorization.is_restricted() {
+                AuthMode::Required
+            } else {
+                authenticator.mode(endpoint.declaration.auth)
+            };
+            let roles = authorization.allow_roles.unwrap_or_default();
+
+            add_security(operation, mode, &schemes, &roles);
+        }
+    }
+
+    api
+}
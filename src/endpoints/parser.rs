@@ -1,12 +1,19 @@
+use std::collections::HashMap;
 use std::fs::DirEntry;
 
 use rstmytype::{ApiProject, ApiEndpointMethod, ApiEndpoint};
 use log::warn;
 
+use crate::auth::api_key::API_KEYS_FILE;
+use crate::db::DATASOURCES_FILE;
+use crate::endpoints::declaration::{Declaration, PROJECT_CONFIG_FILE, ProjectConfig};
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum EndpointMethod {
     GET,
     POST,
+    /// served on GET as `text/event-stream`
+    SSE,
 }
 
 #[derive(Debug)]
@@ -14,8 +21,10 @@ pub struct Endpoint {
     pub tag: String,
     pub method: EndpointMethod,
     pub url_path: String,
+    pub file_path: String,
     pub file_content: String,
-    pub schema: String
+    pub schema: String,
+    pub declaration: Declaration,
 }
 
 impl Endpoint {
@@ -32,13 +41,22 @@ impl Endpoint {
         } else {
             "".to_string()
         };
+        let declaration = match Declaration::parse(&schema) {
+            Ok(declaration) => declaration,
+            Err(e) => {
+                warn!("Skipping endpoint {}: {}", file_path, e);
+                return None;
+            }
+        };
 
         Some(Endpoint {
             tag,
             method,
             url_path,
+            file_path: file_path.clone(),
             file_content: content,
             schema,
+            declaration,
         })
     }
 
@@ -71,6 +89,7 @@ impl Endpoint {
 pub struct Project {
     pub project_name: String,
     pub endpoints: Vec<Endpoint>,
+    pub config: ProjectConfig,
 }
 
 impl Project {
@@ -132,6 +151,13 @@ impl Project {
         Project::load_enpoints(&rel_path, rel_path.clone(), e, &EndpointMethod::POST)
     }
 
+    fn load_sse_endpoints(
+        rel_path: &str,
+        e: &DirEntry,
+    ) -> Option<Box<dyn Iterator<Item = Endpoint>>> {
+        Project::load_enpoints(rel_path, rel_path.to_string(), e, &EndpointMethod::SSE)
+    }
+
     pub fn parse_from_dir_entry(entry: &DirEntry) -> Option<Project> {
         let name = entry.file_name().to_str()?.to_string();
         let paths = std::fs::read_dir(entry.path()).ok()?;
@@ -139,6 +165,10 @@ impl Project {
         let iter = paths
             .flat_map(|e| e.ok())
             .filter(|e| {
+                if e.path().is_file() && e.file_name() == PROJECT_CONFIG_FILE {
+                    return false;
+                }
+
                 if e.path().is_file() {
                     warn!(
                         "Skipping project {} has unexpected file {}",
@@ -149,7 +179,7 @@ impl Project {
                 }
 
                 if let Some(file_str) = e.file_name().to_str() {
-                    if ["GET", "POST"].contains(&file_str) {
+                    if ["GET", "POST", "SSE"].contains(&file_str) {
                         true
                     } else {
                         warn!("Skipping project {} unsupported method {}", name, file_str);
@@ -165,16 +195,26 @@ impl Project {
                     Project::load_get_enpoints(&name, &e)
                 } else if e.path().ends_with("POST") {
                     Project::load_post_endpoints(&name, &e)
+                } else if e.path().ends_with("SSE") {
+                    Project::load_sse_endpoints(&name, &e)
                 } else {
                     panic!("something went wrong in parse_from_dir_entry")
                 }
             })
             .flat_map(|r| r)
             .reduce(|a, b| Box::new(a.chain(b)))?;
+        let config = match ProjectConfig::load(&entry.path()) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("Skipping project {}: {}", name, e);
+                return None;
+            }
+        };
 
         Some(Project {
             project_name: name,
             endpoints: iter.collect(),
+            config,
         })
     }
 }
@@ -194,6 +234,10 @@ impl EndpointCollections {
             .flat_map(|r| r.into_iter())
             .flat_map(|e| e.ok())
             .filter(|e| {
+                if e.file_name() == API_KEYS_FILE || e.file_name() == DATASOURCES_FILE {
+                    return false;
+                }
+
                 let valid = e.path().is_dir();
                 if !valid {
                     warn!(
@@ -211,6 +255,42 @@ impl EndpointCollections {
     }
 }
 
+/// GET endpoints by url path and project settings by project name, what the
+/// endpoints are resolved against when the routes are built.
+pub struct EndpointIndex<'a> {
+    pub get_endpoints: HashMap<String, &'a Endpoint>,
+    projects: HashMap<&'a str, &'a ProjectConfig>,
+    default_project: ProjectConfig,
+}
+
+impl<'a> EndpointIndex<'a> {
+    pub fn new(collection: &'a EndpointCollections) -> EndpointIndex<'a> {
+        EndpointIndex {
+            get_endpoints: collection
+                .projects
+                .iter()
+                .flat_map(|p| &p.endpoints)
+                .filter(|e| e.method == EndpointMethod::GET)
+                .map(|e| (e.url_path.clone(), e))
+                .collect(),
+            projects: collection
+                .projects
+                .iter()
+                .map(|p| (p.project_name.as_str(), &p.config))
+                .collect(),
+            default_project: ProjectConfig::default(),
+        }
+    }
+
+    /// Settings of the endpoint project.
+    pub fn project(&self, endpoint: &Endpoint) -> &ProjectConfig {
+        self.projects
+            .get(endpoint.tag.as_str())
+            .copied()
+            .unwrap_or(&self.default_project)
+    }
+}
+
 impl std::fmt::Display for EndpointCollections {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let projects_strings: Vec<String> =
@@ -246,7 +326,7 @@ impl ApiEndpoint for Endpoint {
 
     fn get_endpoint_method(&self) -> &ApiEndpointMethod {
         match self.method {
-            EndpointMethod::GET => &ApiEndpointMethod::Get,
+            EndpointMethod::GET | EndpointMethod::SSE => &ApiEndpointMethod::Get,
             EndpointMethod::POST => &ApiEndpointMethod::Post
         }
     }
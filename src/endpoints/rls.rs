@@ -0,0 +1,166 @@
+use anyhow::anyhow;
+use serde_json::{Value, json};
+use sqlx::PgConnection;
+
+use crate::auth::policy::claim;
+use crate::endpoints::context::RequestContext;
+use crate::endpoints::declaration::RlsConfig;
+
+/// Longest identifier Postgres keeps, NAMEDATALEN - 1.
+const MAX_ROLE_LEN: usize = 63;
+
+/// Hands the caller over to Postgres row-level security policies: the
+/// endpoint SQL runs in a transaction where the role is switched to the one
+/// from the JWT claims and the claims are available through
+/// `current_setting('request.jwt.claims')`. Both settings are local to the
+/// transaction, so the connection goes back to the pool clean.
+#[derive(Debug, Clone)]
+pub struct RowLevelSecurity {
+    role_claim: String,
+    anon_role: Option<String>,
+    claims_setting: String,
+}
+
+impl RowLevelSecurity {
+    pub fn new(args: &crate::args::types::Args, project: &RlsConfig) -> Option<RowLevelSecurity> {
+        if !project.enabled.unwrap_or(args.rls) {
+            return None;
+        }
+
+        Some(RowLevelSecurity {
+            role_claim: project
+                .role_claim
+                .clone()
+                .unwrap_or_else(|| args.rls_role_claim.clone()),
+            anon_role: project
+                .anon_role
+                .clone()
+                .or_else(|| args.rls_anon_role.clone()),
+            claims_setting: project
+                .claims_setting
+                .clone()
+                .unwrap_or_else(|| args.rls_claims_setting.clone()),
+        })
+    }
+
+    fn role(&self, claims: &Value) -> Option<String> {
+        match claim(claims, &self.role_claim).and_then(|r| r.as_str()) {
+            Some(role) => Some(role.to_string()),
+            None => self.anon_role.clone(),
+        }
+    }
+
+    /// Claims and role the endpoint SQL runs with.
+    pub fn settings(&self, context: &RequestContext) -> anyhow::Result<(Value, String)> {
+        let claims = context
+            .identity
+            .as_ref()
+            .map_or_else(|| json!({}), |i| i.claims.clone());
+        let role = self
+            .role(&claims)
+            .ok_or_else(|| anyhow!("no database role for the request"))?;
+
+        if role.is_empty() || role.len() > MAX_ROLE_LEN || role.chars().any(|c| c.is_control()) {
+            return Err(anyhow!("invalid database role {:?}", role));
+        }
+        // the predefined roles grant server wide privileges
+        if role.starts_with("pg_") {
+            return Err(anyhow!("database role {} can not be switched to", role));
+        }
+
+        Ok((claims, role))
+    }
+
+    /// Must be called inside the transaction running the endpoint SQL.
+    pub async fn apply(
+        &self,
+        conn: &mut PgConnection,
+        context: &RequestContext,
+    ) -> anyhow::Result<()> {
+        let (claims, role) = self.settings(context)?;
+
+        sqlx::query("SELECT set_config($1, $2, true), set_config('role', $3, true)")
+            .bind(&self.claims_setting)
+            .bind(claims.to_string())
+            .bind(role)
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::types::Args;
+    use crate::auth::Identity;
+
+    fn rls(anon_role: Option<&str>) -> RowLevelSecurity {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl", "--rls"]);
+        let project = RlsConfig {
+            anon_role: anon_role.map(|r| r.to_string()),
+            ..Default::default()
+        };
+        RowLevelSecurity::new(&args, &project).unwrap()
+    }
+
+    fn context(claims: Value) -> RequestContext {
+        RequestContext {
+            identity: Some(Identity {
+                claims,
+                api_key: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_role_from_claim() {
+        let claims = json!({"sub": "alice", "role": "reporting"});
+        let (settings_claims, role) = rls(None).settings(&context(claims.clone())).unwrap();
+
+        assert_eq!(role, "reporting");
+        assert_eq!(settings_claims, claims);
+    }
+
+    #[test]
+    fn test_nested_role_claim() {
+        let args = Args::parse_from(["rstsql", "-d", "./test_dsl", "--rls"]);
+        let project = RlsConfig {
+            role_claim: Some("realm_access.role".to_string()),
+            ..Default::default()
+        };
+        let rls = RowLevelSecurity::new(&args, &project).unwrap();
+
+        // the same claim the allow_roles policy checks
+        let claims = json!({"sub": "alice", "realm_access": {"role": "reporting"}});
+        let (_, role) = rls.settings(&context(claims)).unwrap();
+        assert_eq!(role, "reporting");
+    }
+
+    #[test]
+    fn test_missing_claim() {
+        assert!(
+            rls(None)
+                .settings(&context(json!({"sub": "alice"})))
+                .is_err()
+        );
+        assert!(rls(None).settings(&RequestContext::default()).is_err());
+
+        let (claims, role) = rls(Some("web_anon"))
+            .settings(&RequestContext::default())
+            .unwrap();
+        assert_eq!(role, "web_anon");
+        assert_eq!(claims, json!({}));
+    }
+
+    #[test]
+    fn test_rejected_roles() {
+        for role in ["", "pg_read_server_files", "bad\nrole", &"r".repeat(64)] {
+            assert!(rls(None).settings(&context(json!({"role": role}))).is_err());
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::time::Duration;
+
+use log::warn;
+use serde_json::{Map, Value, json};
+use sqlx::postgres::PgArguments;
+use sqlx::query::Query;
+use sqlx::{Connection, PgConnection, Postgres, Row};
+
+use crate::access_log::request_id;
+use crate::endpoints::context::is_context_param;
+use crate::endpoints::parser::{Endpoint, EndpointMethod};
+
+const REDACTED: &str = "***";
+
+/// Logs the executions of an endpoint SQL running longer than
+/// `--slow-query-threshold`.
+#[derive(Debug, Clone)]
+pub struct SlowQueryLog {
+    threshold: Duration,
+    /// 0 but for GET endpoints, whose SQL can be run a second time
+    explain_rate: f64,
+    /// parameters marked `sensitive: true` in the allowlist
+    sensitive: Vec<String>,
+}
+
+impl SlowQueryLog {
+    pub fn new(endpoint: &Endpoint, args: &crate::args::types::Args) -> Option<SlowQueryLog> {
+        Some(SlowQueryLog {
+            threshold: args.slow_query_threshold?,
+            explain_rate: match endpoint.method {
+                EndpointMethod::GET => args.slow_query_explain_rate,
+                _ => 0.0,
+            },
+            sensitive: endpoint.declaration.allowlist.sensitive(),
+        })
+    }
+
+    pub fn is_slow(&self, elapsed: Duration) -> bool {
+        elapsed >= self.threshold
+    }
+
+    pub fn sample_explain(&self) -> bool {
+        self.explain_rate > 0.0 && rand::random::<f64>() < self.explain_rate
+    }
+
+    /// Masks the sensitive parameters and the ones taken from the request,
+    /// which carry headers, API keys and claims.
+    fn redact(&self, args: &[(&String, Option<&Value>)]) -> Value {
+        let params: Map<String, Value> = args
+            .iter()
+            .map(|(name, value)| {
+                let value = if self.sensitive.contains(name) || is_context_param(name) {
+                    json!(REDACTED)
+                } else {
+                    value.cloned().unwrap_or(Value::Null)
+                };
+                (name.to_string(), value)
+            })
+            .collect();
+
+        Value::Object(params)
+    }
+
+    pub fn log(
+        &self,
+        file_path: &str,
+        sql: &str,
+        args: &[(&String, Option<&Value>)],
+        elapsed: Duration,
+        plan: Option<String>,
+        error: Option<&anyhow::Error>,
+    ) {
+        let outcome = match error {
+            Some(e) => format!("failed after {:?}: {}", elapsed, e),
+            None => format!("took {:?}", elapsed),
+        };
+        let mut message = format!(
+            "request {}: slow query {} {}\nparams: {}\n{}",
+            request_id().unwrap_or_default(),
+            file_path,
+            outcome,
+            self.redact(args),
+            sql.trim()
+        );
+        if let Some(plan) = plan {
+            message.push_str("\nplan:\n");
+            message.push_str(&plan);
+        }
+
+        warn!(target: "slow_query", "{}", message);
+    }
+}
+
+/// `query` must be the bound `EXPLAIN (ANALYZE, BUFFERS)` statement. ANALYZE
+/// runs the statement again, so it runs in a savepoint that is rolled back.
+pub async fn explain(
+    conn: &mut PgConnection,
+    query: Query<'_, Postgres, PgArguments>,
+) -> anyhow::Result<String> {
+    let mut tx = conn.begin().await?;
+    let rows = query.fetch_all(&mut *tx).await?;
+    tx.rollback().await?;
+
+    let lines = rows
+        .iter()
+        .map(|r| r.try_get::<String, _>(0))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(lines.join("\n"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let log = SlowQueryLog {
+            threshold: Duration::from_millis(500),
+            explain_rate: 0.0,
+            sensitive: vec!["password".to_string()],
+        };
+        let (login, password, missing) = (
+            "login".to_string(),
+            "password".to_string(),
+            "missing".to_string(),
+        );
+        let (header, api_key, claim) = (
+            "_header.authorization".to_string(),
+            "_api_key".to_string(),
+            "_claims.email".to_string(),
+        );
+        let (alice, secret) = (json!("alice"), json!("secret"));
+
+        assert_eq!(
+            log.redact(&[
+                (&login, Some(&alice)),
+                (&password, Some(&secret)),
+                (&missing, None),
+                (&header, Some(&secret)),
+                (&api_key, Some(&secret)),
+                (&claim, Some(&alice)),
+            ]),
+            json!({
+                "login": "alice",
+                "password": "***",
+                "missing": null,
+                "_header.authorization": "***",
+                "_api_key": "***",
+                "_claims.email": "***",
+            })
+        );
+        assert!(log.is_slow(Duration::from_secs(1)));
+        assert!(!log.sample_explain());
+    }
+}
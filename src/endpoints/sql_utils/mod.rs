@@ -1,3 +1,4 @@
 pub mod json_to_params;
 pub mod preprocess;
+pub mod query_syntax;
 pub mod row_to_json;
@@ -1,8 +1,43 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::endpoints::context::CONTEXT_NAMESPACES;
+
 pub fn rewrite_sql_with_named_params(sql: &str) -> (String, Vec<String>) {
-    let mut result = String::with_capacity(sql.len());
     let mut params = Vec::new();
+    let result = replace_named_params(sql, |name| {
+        params.push(name.to_string());
+        format!("${}", params.len())
+    });
+
+    (result, params)
+}
+
+pub fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Strips the statement terminator so the SQL can be embedded as a subquery.
+pub fn as_subquery(sql: &str) -> &str {
+    sql.trim_end().trim_end_matches(';')
+}
+
+fn is_context_path(name: &str, chars: &Peekable<Chars>) -> bool {
+    let root = name.split('.').next().unwrap_or_default();
+    let mut ahead = chars.clone();
+    ahead.next(); // the dot itself
+
+    CONTEXT_NAMESPACES.contains(&root)
+        && ahead
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphabetic() || *c == '_')
+}
+
+/// Replaces every `:name` occurrence with the output of `replace`, leaving
+/// `::` typecasts untouched.
+pub fn replace_named_params(sql: &str, mut replace: impl FnMut(&str) -> String) -> String {
+    let mut result = String::with_capacity(sql.len());
     let mut chars = sql.chars().peekable();
-    let mut index = 0;
 
     while let Some(c) = chars.next() {
         if c == ':' {
@@ -29,6 +64,10 @@ pub fn rewrite_sql_with_named_params(sql: &str) -> (String, Vec<String>) {
                     if nc.is_ascii_alphanumeric() || nc == '_' {
                         name.push(nc);
                         chars.next();
+                    } else if nc == '.' && is_context_path(&name, &chars) {
+                        // e.g. ":_claims.sub", the path continues after the dot
+                        name.push(nc);
+                        chars.next();
                     } else {
                         break;
                     }
@@ -36,10 +75,7 @@ pub fn rewrite_sql_with_named_params(sql: &str) -> (String, Vec<String>) {
             }
 
             if !name.is_empty() {
-                index += 1;
-                params.push(name);
-                result.push('$');
-                result.push_str(&index.to_string());
+                result.push_str(&replace(&name));
                 continue;
             } else {
                 // lone ":" not followed by identifier
@@ -52,5 +88,23 @@ pub fn rewrite_sql_with_named_params(sql: &str) -> (String, Vec<String>) {
         result.push(c);
     }
 
-    (result.clone(), params)
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_sql_with_named_params() {
+        let (sql, params) = rewrite_sql_with_named_params(
+            "SELECT :a::int, :_claims.org.id, :_header.x_tenant::text, :_ip, :b.c, ':'",
+        );
+
+        assert_eq!(sql, "SELECT $1::int, $2, $3::text, $4, $5.c, ':'");
+        assert_eq!(
+            params,
+            vec!["a", "_claims.org.id", "_header.x_tenant", "_ip", "b"]
+        );
+    }
 }
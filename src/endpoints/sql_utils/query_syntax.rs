@@ -0,0 +1,491 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+
+use itertools::Itertools;
+
+use crate::endpoints::declaration::Field;
+use crate::endpoints::sql_utils::preprocess::{as_subquery, quote_ident};
+
+pub const ORDER_PARAM: &str = "order";
+pub const SELECT_PARAM: &str = "select";
+
+#[derive(Debug)]
+pub struct QuerySyntaxError {
+    message: String,
+}
+
+impl QuerySyntaxError {
+    fn new(message: String) -> Self {
+        Self { message }
+    }
+}
+
+impl Display for QuerySyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for QuerySyntaxError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operator {
+    Eq,
+    Neq,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Like,
+    Ilike,
+    In,
+    Is,
+}
+
+impl Operator {
+    fn parse(op: &str) -> Option<Operator> {
+        match op {
+            "eq" => Some(Operator::Eq),
+            "neq" => Some(Operator::Neq),
+            "gt" => Some(Operator::Gt),
+            "gte" => Some(Operator::Gte),
+            "lt" => Some(Operator::Lt),
+            "lte" => Some(Operator::Lte),
+            "like" => Some(Operator::Like),
+            "ilike" => Some(Operator::Ilike),
+            "in" => Some(Operator::In),
+            "is" => Some(Operator::Is),
+            _ => None,
+        }
+    }
+
+    fn sql(&self) -> &'static str {
+        match self {
+            Operator::Eq => "=",
+            Operator::Neq => "<>",
+            Operator::Gt => ">",
+            Operator::Gte => ">=",
+            Operator::Lt => "<",
+            Operator::Lte => "<=",
+            Operator::Like => "LIKE",
+            Operator::Ilike => "ILIKE",
+            Operator::In => "= ANY",
+            Operator::Is => "IS",
+        }
+    }
+}
+
+/// Value bound after the endpoint's own parameters, always as text and cast
+/// on the SQL side to the declared field type.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterValue {
+    Text(String),
+    List(Vec<String>),
+}
+
+#[derive(Debug)]
+struct Filter {
+    field: String,
+    cast: Option<&'static str>,
+    negated: bool,
+    op: Operator,
+    value: String,
+}
+
+#[derive(Debug)]
+struct OrderTerm {
+    field: String,
+    descending: bool,
+    nulls: Option<&'static str>,
+}
+
+/// PostgREST-like projection, filtering and ordering requested through the
+/// query string, e.g. `?select=id,status&order=created_at.desc&amount=gt.100`.
+#[derive(Debug, Default)]
+pub struct QueryShape {
+    select: Vec<String>,
+    filters: Vec<Filter>,
+    order: Vec<OrderTerm>,
+}
+
+fn field_cast(field: &Field) -> Result<Option<&'static str>, QuerySyntaxError> {
+    match field.field_type.as_str() {
+        "integer" => Ok(Some("bigint")),
+        "number" => Ok(Some("numeric")),
+        "boolean" => Ok(Some("boolean")),
+        "object" | "array" => Err(QuerySyntaxError::new(format!(
+            "field {} can not be filtered",
+            field.field
+        ))),
+        _ => Ok(None),
+    }
+}
+
+fn parse_filter(field: &Field, raw: &str) -> Result<Filter, QuerySyntaxError> {
+    let invalid = || QuerySyntaxError::new(format!("invalid filter {}={}", field.field, raw));
+
+    let (mut op, mut value) = raw.split_once('.').ok_or_else(invalid)?;
+    let negated = op == "not";
+    if negated {
+        (op, value) = value.split_once('.').ok_or_else(invalid)?;
+    }
+    let op = Operator::parse(op).ok_or_else(invalid)?;
+
+    if op == Operator::Is && !["null", "true", "false"].contains(&value) {
+        return Err(invalid());
+    }
+    let cast = field_cast(field)?;
+    // IS TRUE/FALSE only type checks on boolean columns
+    if op == Operator::Is && value != "null" && cast != Some("boolean") {
+        return Err(QuerySyntaxError::new(format!(
+            "field {} is not a boolean, is.{} is not supported",
+            field.field, value
+        )));
+    }
+
+    Ok(Filter {
+        field: field.field.clone(),
+        cast,
+        negated,
+        op,
+        value: value.to_string(),
+    })
+}
+
+fn parse_order_term(fields: &[Field], raw: &str) -> Result<OrderTerm, QuerySyntaxError> {
+    let mut name = raw;
+    let mut descending = false;
+    let mut nulls = None;
+
+    // modifiers are stripped from the end so that dotted field names survive
+    loop {
+        if let Some(rest) = name.strip_suffix(".asc") {
+            name = rest;
+        } else if let Some(rest) = name.strip_suffix(".desc") {
+            name = rest;
+            descending = true;
+        } else if let Some(rest) = name.strip_suffix(".nullsfirst") {
+            name = rest;
+            nulls = Some("NULLS FIRST");
+        } else if let Some(rest) = name.strip_suffix(".nullslast") {
+            name = rest;
+            nulls = Some("NULLS LAST");
+        } else {
+            break;
+        }
+    }
+
+    if !fields.iter().any(|f| f.field == name) {
+        return Err(QuerySyntaxError::new(format!(
+            "can not order by unknown field {}",
+            name
+        )));
+    }
+
+    Ok(OrderTerm {
+        field: name.to_string(),
+        descending,
+        nulls,
+    })
+}
+
+fn parse_select(fields: &[Field], raw: &str) -> Result<Vec<String>, QuerySyntaxError> {
+    let mut select = Vec::new();
+
+    for name in raw.split(',').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+        if !fields.iter().any(|f| f.field == name) {
+            return Err(QuerySyntaxError::new(format!(
+                "can not select unknown field {}",
+                name
+            )));
+        }
+        if !select.iter().any(|s| s == name) {
+            select.push(name.to_string());
+        }
+    }
+
+    Ok(select)
+}
+
+impl QueryShape {
+    /// Extracts the requested projection and, when `filtering` is enabled,
+    /// filters and ordering from the query string. Only declared response
+    /// fields are accepted, and keys consumed by the endpoint SQL itself
+    /// (`sql_params`) are never interpreted.
+    pub fn parse(
+        params: &HashMap<String, String>,
+        fields: &[Field],
+        sql_params: &[String],
+        filtering: bool,
+    ) -> Result<QueryShape, QuerySyntaxError> {
+        let mut shape = QueryShape::default();
+
+        if let Some(raw) = params.get(SELECT_PARAM)
+            && !sql_params.iter().any(|p| p == SELECT_PARAM)
+        {
+            shape.select = parse_select(fields, raw)?;
+        }
+
+        if !filtering {
+            return Ok(shape);
+        }
+
+        for field in fields {
+            if sql_params.contains(&field.field) {
+                continue;
+            }
+            if let Some(raw) = params.get(&field.field) {
+                shape.filters.push(parse_filter(field, raw)?);
+            }
+        }
+
+        if let Some(raw) = params.get(ORDER_PARAM)
+            && !sql_params.iter().any(|p| p == ORDER_PARAM)
+        {
+            for term in raw.split(',').filter(|t| !t.is_empty()) {
+                shape.order.push(parse_order_term(fields, term)?);
+            }
+        }
+
+        Ok(shape)
+    }
+
+    /// Requested projection, empty when all the fields are returned.
+    pub fn selected(&self) -> &[String] {
+        &self.select
+    }
+
+    /// Swaps a field computed after the query (e.g. an embed) in the
+    /// projection for the columns it is computed from.
+    pub fn replace_computed(&mut self, field: &str, sources: &[String]) {
+        let Some(pos) = self.select.iter().position(|s| s == field) else {
+            return;
+        };
+
+        self.select.remove(pos);
+        for source in sources {
+            if !self.select.contains(source) {
+                self.select.push(source.clone());
+            }
+        }
+    }
+
+    /// Keeps a column read from the rows but not requested (e.g. a
+    /// validator) in a restricted projection.
+    pub fn include(&mut self, column: &str) {
+        if !self.select.is_empty() && !self.select.iter().any(|s| s == column) {
+            self.select.push(column.to_string());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.select.is_empty() && self.filters.is_empty() && self.order.is_empty()
+    }
+
+    /// Wraps the endpoint SQL into a subquery, projecting only the selected
+    /// columns so Postgres can skip computing the others, and appends the
+    /// predicates.
+    /// `first_param` is the first free positional parameter index, the
+    /// returned values must be bound in order starting from it.
+    pub fn wrap_sql(&self, sql: &str, first_param: usize) -> (String, Vec<FilterValue>) {
+        let sql = as_subquery(sql);
+        let mut values = Vec::with_capacity(self.filters.len());
+        let mut predicates = Vec::with_capacity(self.filters.len());
+
+        for filter in &self.filters {
+            let column = match filter.cast {
+                Some(_) => quote_ident(&filter.field),
+                None => format!("{}::text", quote_ident(&filter.field)),
+            };
+            let cast = filter.cast.unwrap_or("text");
+            let index = first_param + values.len();
+
+            let predicate = match filter.op {
+                Operator::Is => format!("{} IS {}", column, filter.value.to_uppercase()),
+                Operator::In => {
+                    let list = filter
+                        .value
+                        .trim_start_matches('(')
+                        .trim_end_matches(')')
+                        .split(',')
+                        .map(|v| v.to_string())
+                        .collect();
+                    values.push(FilterValue::List(list));
+                    format!("{} = ANY(${}::text[]::{}[])", column, index, cast)
+                }
+                Operator::Like | Operator::Ilike => {
+                    values.push(FilterValue::Text(filter.value.replace('*', "%")));
+                    format!(
+                        "{}::text {} ${}",
+                        quote_ident(&filter.field),
+                        filter.op.sql(),
+                        index
+                    )
+                }
+                _ => {
+                    values.push(FilterValue::Text(filter.value.clone()));
+                    format!("{} {} ${}::{}", column, filter.op.sql(), index, cast)
+                }
+            };
+
+            if filter.negated {
+                predicates.push(format!("NOT ({})", predicate));
+            } else {
+                predicates.push(predicate);
+            }
+        }
+
+        let columns = if self.select.is_empty() {
+            "*".to_string()
+        } else {
+            self.select.iter().map(|c| quote_ident(c)).join(", ")
+        };
+
+        let mut wrapped = format!("SELECT {} FROM (\n{}\n) AS \"_rstsql\"", columns, sql);
+        if !predicates.is_empty() {
+            wrapped.push_str(" WHERE ");
+            wrapped.push_str(&predicates.join(" AND "));
+        }
+        if !self.order.is_empty() {
+            let terms: Vec<String> = self
+                .order
+                .iter()
+                .map(|t| {
+                    let mut term = quote_ident(&t.field);
+                    if t.descending {
+                        term.push_str(" DESC");
+                    }
+                    if let Some(nulls) = t.nulls {
+                        term.push(' ');
+                        term.push_str(nulls);
+                    }
+                    term
+                })
+                .collect();
+            wrapped.push_str(" ORDER BY ");
+            wrapped.push_str(&terms.join(", "));
+        }
+
+        (wrapped, values)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fields() -> Vec<Field> {
+        vec![
+            Field {
+                field: "status".to_string(),
+                field_type: "string".to_string(),
+            },
+            Field {
+                field: "amount".to_string(),
+                field_type: "integer".to_string(),
+            },
+            Field {
+                field: "payload".to_string(),
+                field_type: "object".to_string(),
+            },
+        ]
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_wrap_filters_and_order() {
+        let shape = QueryShape::parse(
+            &params(&[("status", "eq.active"), ("order", "amount.desc.nullslast")]),
+            &fields(),
+            &[],
+            true,
+        )
+        .unwrap();
+
+        let (sql, values) = shape.wrap_sql("SELECT 1;\n", 2);
+        assert_eq!(
+            sql,
+            "SELECT * FROM (\nSELECT 1\n) AS \"_rstsql\" WHERE \"status\"::text = $2::text ORDER BY \"amount\" DESC NULLS LAST"
+        );
+        assert_eq!(values, vec![FilterValue::Text("active".to_string())]);
+    }
+
+    #[test]
+    fn test_in_and_negation() {
+        let shape = QueryShape::parse(&params(&[("amount", "not.in.(1,2)")]), &fields(), &[], true)
+            .unwrap();
+
+        let (sql, values) = shape.wrap_sql("SELECT 1", 1);
+        assert!(sql.ends_with("WHERE NOT (\"amount\" = ANY($1::text[]::bigint[]))"));
+        assert_eq!(
+            values,
+            vec![FilterValue::List(vec!["1".to_string(), "2".to_string()])]
+        );
+    }
+
+    #[test]
+    fn test_sql_params_are_not_filters() {
+        let shape = QueryShape::parse(
+            &params(&[("status", "active"), ("order", "whatever")]),
+            &fields(),
+            &["status".to_string(), "order".to_string()],
+            true,
+        )
+        .unwrap();
+
+        assert!(shape.is_empty());
+    }
+
+    #[test]
+    fn test_select() {
+        let mut shape = QueryShape::parse(
+            &params(&[("select", "amount,status,amount"), ("status", "eq.x")]),
+            &fields(),
+            &[],
+            false,
+        )
+        .unwrap();
+
+        let (sql, values) = shape.wrap_sql("SELECT 1", 1);
+        assert_eq!(
+            sql,
+            "SELECT \"amount\", \"status\" FROM (\nSELECT 1\n) AS \"_rstsql\""
+        );
+        assert!(values.is_empty());
+        assert!(QueryShape::parse(&params(&[("select", "id")]), &fields(), &[], false).is_err());
+
+        shape.include("version");
+        shape.include("amount");
+        assert_eq!(shape.selected(), ["amount", "status", "version"]);
+
+        let mut all = QueryShape::default();
+        all.include("version");
+        assert!(all.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_invalid_input() {
+        assert!(QueryShape::parse(&params(&[("status", "active")]), &fields(), &[], true).is_err());
+        assert!(QueryShape::parse(&params(&[("status", "eqq.x")]), &fields(), &[], true).is_err());
+        assert!(QueryShape::parse(&params(&[("payload", "eq.x")]), &fields(), &[], true).is_err());
+        assert!(
+            QueryShape::parse(&params(&[("amount", "is.maybe")]), &fields(), &[], true).is_err()
+        );
+        assert!(
+            QueryShape::parse(&params(&[("status", "is.true")]), &fields(), &[], true).is_err()
+        );
+        assert!(
+            QueryShape::parse(&params(&[("status", "is.null")]), &fields(), &[], true).is_ok()
+        );
+        assert!(
+            QueryShape::parse(&params(&[("order", "unknown.desc")]), &fields(), &[], true).is_err()
+        );
+    }
+}
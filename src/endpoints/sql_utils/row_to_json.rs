@@ -6,6 +6,10 @@ use sqlx::{
     types::{Uuid, chrono},
 };
 
+/// Results with at least as many rows are converted on the blocking pool, so
+/// that large results do not hold up the other requests of a worker.
+const BLOCKING_ROWS: usize = 1000;
+
 /// Best-effort decode of a cell into JSON.
 /// We try a set of common types. If all fail, fall back to string.
 fn cell_to_json(row: &PgRow, idx: usize) -> Value {
@@ -83,3 +87,18 @@ pub fn row_to_json(row: &PgRow) -> Value {
 
     Value::Object(obj)
 }
+
+/// Value of the named column, `None` when the result has no such column.
+pub fn column_to_json(row: &PgRow, column: &str) -> Option<Value> {
+    let idx = row.columns().iter().position(|c| c.name() == column)?;
+
+    Some(cell_to_json(row, idx))
+}
+
+pub async fn rows_to_json(rows: Vec<PgRow>) -> anyhow::Result<Vec<Value>> {
+    if rows.len() < BLOCKING_ROWS {
+        return Ok(rows.iter().map(row_to_json).collect());
+    }
+
+    Ok(tokio::task::spawn_blocking(move || rows.iter().map(row_to_json).collect()).await?)
+}
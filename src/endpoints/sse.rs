@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::Stream;
+use log::{debug, warn};
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use tokio::sync::broadcast::{Receiver, error::RecvError};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use crate::endpoints::context::RequestContext;
+use crate::endpoints::handler::EndpointHandler;
+use crate::endpoints::parser::Endpoint;
+use crate::notify::{NotifyEvent, NotifyHub};
+
+/// Streams the notifications of the declared channel to each client. When
+/// the endpoint file has SQL below the declaration, it runs for every
+/// notification with `:_payload` bound to the payload and its result is sent
+/// instead, an empty result sends nothing.
+#[derive(Clone)]
+pub struct SseHandler {
+    channel: String,
+    query: Option<EndpointHandler>,
+    /// response fields the clients can filter the payloads on
+    filter_fields: Vec<String>,
+    heartbeat: Duration,
+    hub: Arc<NotifyHub>,
+    /// the streams end once shutdown started
+    draining: watch::Receiver<bool>,
+}
+
+fn has_sql(endpoint: &Endpoint) -> bool {
+    let content = &endpoint.file_content;
+    let body = if Endpoint::contains_schema(content) {
+        content.split_once("*/").map_or("", |(_, b)| b)
+    } else {
+        content
+    };
+
+    !body.trim().is_empty()
+}
+
+/// Without SQL the query parameters naming response fields must equal the
+/// payload fields.
+fn payload_matches(payload: &str, filters: &[(&String, &String)]) -> bool {
+    if filters.is_empty() {
+        return true;
+    }
+
+    let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(payload) else {
+        return false;
+    };
+    filters
+        .iter()
+        .all(|(name, expected)| match fields.get(*name) {
+            Some(Value::String(v)) => v == *expected,
+            Some(v) => serde_json::from_str::<Value>(expected).is_ok_and(|e| e == *v),
+            None => false,
+        })
+}
+
+struct Subscription {
+    handler: SseHandler,
+    events: Receiver<NotifyEvent>,
+    params: HashMap<String, String>,
+    context: RequestContext,
+    pool: PgPool,
+    draining: watch::Receiver<bool>,
+    /// `exp` of the token, checked when the stream opened only
+    expires_at: Option<Instant>,
+    /// the stream ends after the expiry error event
+    expired: bool,
+}
+
+impl Subscription {
+    async fn event(&self, payload: String) -> Option<Event> {
+        let event = Event::default();
+        match self
+            .handler
+            .render(payload, &self.params, &self.context, &self.pool)
+            .await?
+        {
+            Ok(Value::String(payload)) => Some(event.event(&self.handler.channel).data(payload)),
+            Ok(result) => Some(event.event(&self.handler.channel).data(result.to_string())),
+            Err(e) => Some(
+                event
+                    .event("error")
+                    .data(json!({"error": format!("{}", e)}).to_string()),
+            ),
+        }
+    }
+
+    async fn next(&mut self) -> Option<Event> {
+        if self.expired {
+            return None;
+        }
+
+        loop {
+            let expires_at = self.expires_at;
+            let event = tokio::select! {
+                event = self.events.recv() => event,
+                _ = self.draining.wait_for(|d| *d) => return None,
+                _ = async {
+                    match expires_at {
+                        Some(at) => tokio::time::sleep_until(at).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    self.expired = true;
+                    return Some(
+                        Event::default()
+                            .event("error")
+                            .data(json!({"error": "token expired"}).to_string()),
+                    );
+                }
+            };
+            match event {
+                Ok(NotifyEvent::Notification { channel, payload })
+                    if channel == self.handler.channel =>
+                {
+                    if let Some(event) = self.event(payload).await {
+                        return Some(event);
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    debug!(
+                        "SSE client of {} missed {} notifications",
+                        self.handler.channel, missed
+                    );
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl SseHandler {
+    pub fn new(
+        endpoint: &Endpoint,
+        handler: EndpointHandler,
+        hub: Arc<NotifyHub>,
+        draining: watch::Receiver<bool>,
+        args: &crate::args::types::Args,
+    ) -> Option<SseHandler> {
+        let declaration = &endpoint.declaration;
+        let Some(channel) = &declaration.channel else {
+            warn!(
+                "Skipping SSE endpoint {} without channel",
+                endpoint.url_path
+            );
+            return None;
+        };
+
+        Some(SseHandler {
+            channel: channel.clone(),
+            query: has_sql(endpoint).then_some(handler),
+            filter_fields: declaration
+                .response
+                .fields
+                .iter()
+                .map(|f| f.field.clone())
+                .collect(),
+            heartbeat: declaration.heartbeat.unwrap_or(args.sse_heartbeat),
+            hub,
+            draining,
+        })
+    }
+
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// Data sent for a notification of the channel, the payload as a string
+    /// without SQL and the rows otherwise. `None` when the notification is
+    /// filtered out.
+    pub async fn render(
+        &self,
+        payload: String,
+        params: &HashMap<String, String>,
+        context: &RequestContext,
+        pool: &PgPool,
+    ) -> Option<anyhow::Result<Value>> {
+        let Some(query) = &self.query else {
+            let filters: Vec<(&String, &String)> = params
+                .iter()
+                .filter(|(k, _)| self.filter_fields.contains(k))
+                .collect();
+            return payload_matches(&payload, &filters).then_some(Ok(Value::String(payload)));
+        };
+
+        let context = RequestContext {
+            payload: Some(payload),
+            ..context.clone()
+        };
+        match query.handle_get(params, &context, pool.clone()).await {
+            Ok(Value::Array(rows)) if rows.is_empty() => None,
+            result => Some(result),
+        }
+    }
+
+    pub fn stream(
+        &self,
+        params: HashMap<String, String>,
+        context: RequestContext,
+        pool: PgPool,
+    ) -> Sse<impl Stream<Item = Result<Event, Infallible>> + use<>> {
+        let expires_at = context
+            .identity
+            .as_ref()
+            .and_then(|i| i.expires_in())
+            .map(|left| Instant::now() + left);
+        let subscription = Subscription {
+            handler: self.clone(),
+            events: self.hub.subscribe(),
+            params,
+            context,
+            pool,
+            draining: self.draining.clone(),
+            expires_at,
+            expired: false,
+        };
+        let stream = futures_util::stream::unfold(subscription, |mut s| async move {
+            s.next().await.map(|event| (Ok(event), s))
+        });
+
+        Sse::new(stream).keep_alive(KeepAlive::new().interval(self.heartbeat).text("heartbeat"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_payload_matches() {
+        let customer = "customer_id".to_string();
+        let seven = "7".to_string();
+        let filters = vec![(&customer, &seven)];
+
+        assert!(payload_matches(
+            r#"{"customer_id": 7, "total": 10}"#,
+            &filters
+        ));
+        assert!(!payload_matches(r#"{"customer_id": 8}"#, &filters));
+        assert!(!payload_matches("not json", &filters));
+        assert!(payload_matches("not json", &[]));
+    }
+}
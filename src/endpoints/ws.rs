@@ -0,0 +1,439 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::http::StatusCode;
+use axum::middleware;
+use axum::routing::{MethodRouter, get};
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Value, json};
+use sqlx::PgPool;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::watch;
+
+use crate::auth::{Authenticator, EndpointAuth, Identity, auth_middleware};
+use crate::endpoints::context::RequestContext;
+use crate::endpoints::error::error_status;
+use crate::endpoints::handler::EndpointHandler;
+use crate::endpoints::sse::SseHandler;
+use crate::limits::EndpointRateLimit;
+use crate::metrics::EndpointMetrics;
+use crate::notify::{NotifyEvent, NotifyHub};
+
+/// GET or POST endpoint callable over the websocket, with the auth, rate
+/// limit and metrics of its HTTP route applied to every call.
+pub struct WsEndpoint {
+    pub handler: EndpointHandler,
+    pub auth: EndpointAuth,
+    pub rate_limit: EndpointRateLimit,
+    pub metrics: EndpointMetrics,
+    /// pool of the endpoint datasource
+    pub pool: PgPool,
+}
+
+/// SSE endpoint subscribed to over the websocket, its notifications go through
+/// the endpoint SQL and filters as on its SSE route.
+pub struct WsStream {
+    pub handler: SseHandler,
+    pub auth: EndpointAuth,
+    pub rate_limit: EndpointRateLimit,
+    /// pool of the endpoint datasource
+    pub pool: PgPool,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    /// Runs an endpoint, `params` is the query string of a GET and the body
+    /// of a POST.
+    Query {
+        #[serde(default)]
+        id: Value,
+        endpoint: String,
+        #[serde(default = "default_method")]
+        method: String,
+        #[serde(default)]
+        params: Value,
+    },
+    /// Subscribes to a `--ws-channels` channel, whose payloads are sent as
+    /// is, or to an SSE endpoint with `params` as its query string.
+    Subscribe {
+        channel: Option<String>,
+        endpoint: Option<String>,
+        #[serde(default)]
+        params: Value,
+    },
+    Unsubscribe {
+        channel: Option<String>,
+        endpoint: Option<String>,
+    },
+}
+
+/// What a websocket session is subscribed to.
+#[derive(Default)]
+struct Subscriptions {
+    channels: HashSet<String>,
+    /// SSE endpoint path -> query string
+    streams: HashMap<String, HashMap<String, String>>,
+}
+
+impl Subscriptions {
+    fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.streams.is_empty()
+    }
+}
+
+fn query_string(params: &Value) -> HashMap<String, String> {
+    let Some(params) = params.as_object() else {
+        return HashMap::new();
+    };
+
+    params
+        .iter()
+        .map(|(k, v)| match v {
+            Value::String(s) => (k.clone(), s.clone()),
+            v => (k.clone(), v.to_string()),
+        })
+        .collect()
+}
+
+/// Websocket route shared by every endpoint, the handshake authenticates the
+/// caller once and each message is authorized against its endpoint.
+pub struct WsRouter {
+    endpoints: HashMap<String, WsEndpoint>,
+    /// SSE endpoints by url path
+    streams: HashMap<String, WsStream>,
+    /// `--ws-channels`, open to every caller
+    public_channels: HashSet<String>,
+    hub: Arc<NotifyHub>,
+    /// the sessions are closed once shutdown started
+    draining: watch::Receiver<bool>,
+}
+
+impl WsRouter {
+    pub fn new(
+        hub: Arc<NotifyHub>,
+        draining: watch::Receiver<bool>,
+        public_channels: &[String],
+    ) -> WsRouter {
+        WsRouter {
+            endpoints: HashMap::new(),
+            streams: HashMap::new(),
+            public_channels: public_channels.iter().cloned().collect(),
+            hub,
+            draining,
+        }
+    }
+
+    /// `key` is `METHOD /url/path`.
+    pub fn add_endpoint(&mut self, key: String, endpoint: WsEndpoint) {
+        self.endpoints.insert(key, endpoint);
+    }
+
+    pub fn add_stream(&mut self, url_path: String, stream: WsStream) {
+        self.streams.insert(url_path, stream);
+    }
+
+    pub fn route<S: Clone + Send + Sync + 'static>(
+        self,
+        authenticator: Arc<Authenticator>,
+        path: &str,
+    ) -> MethodRouter<S> {
+        let router = Arc::new(self);
+        let auth_layer = middleware::from_fn_with_state(
+            EndpointAuth::handshake(authenticator, path),
+            auth_middleware,
+        );
+
+        get(
+            move |context: RequestContext, upgrade: WebSocketUpgrade| async move {
+                upgrade.on_upgrade(move |socket| router.session(socket, context))
+            },
+        )
+        .layer(auth_layer)
+    }
+
+    async fn query(
+        &self,
+        endpoint: &str,
+        method: &str,
+        params: &Value,
+        context: &RequestContext,
+    ) -> Result<Value, String> {
+        let key = format!("{} {}", method.to_uppercase(), endpoint);
+        let Some(endpoint) = self.endpoints.get(&key) else {
+            return Err(format!("unknown endpoint {}", key));
+        };
+
+        let start = Instant::now();
+        let res = Self::call(endpoint, key.starts_with("POST"), params, context).await;
+        match &res {
+            Ok(_) => endpoint
+                .metrics
+                .observe(StatusCode::OK, false, start.elapsed()),
+            Err((status, _)) => endpoint.metrics.observe(*status, true, start.elapsed()),
+        }
+
+        res.map_err(|(_, error)| error)
+    }
+
+    async fn call(
+        endpoint: &WsEndpoint,
+        post: bool,
+        params: &Value,
+        context: &RequestContext,
+    ) -> Result<Value, (StatusCode, String)> {
+        endpoint
+            .auth
+            .authorize(context.identity.as_ref())
+            .map_err(|e| (e.status(), e.message().to_string()))?;
+        endpoint
+            .rate_limit
+            .check(context.identity.as_ref(), context.ip)
+            .map_err(|_| {
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    "rate limit exceeded".to_string(),
+                )
+            })?;
+
+        let res = if post {
+            endpoint
+                .handler
+                .handle_post(params, &HashMap::new(), context, endpoint.pool.clone())
+                .await
+        } else {
+            endpoint
+                .handler
+                .handle_get(&query_string(params), context, endpoint.pool.clone())
+                .await
+        };
+
+        res.map_err(|e| (error_status(&e), format!("{}", e)))
+    }
+
+    /// The auth and rate limit of an SSE endpoint apply once per
+    /// subscription, as they do once per SSE connection.
+    fn subscribe(
+        &self,
+        channel: Option<String>,
+        endpoint: Option<String>,
+        params: &Value,
+        subscriptions: &mut Subscriptions,
+        context: &RequestContext,
+    ) -> Result<Value, String> {
+        match (channel, endpoint) {
+            (Some(channel), None) => {
+                if !self.public_channels.contains(&channel) {
+                    return Err(format!("unknown channel {}", channel));
+                }
+                subscriptions.channels.insert(channel.clone());
+
+                Ok(json!({"type": "subscribed", "channel": channel}))
+            }
+            (None, Some(endpoint)) => {
+                let Some(stream) = self.streams.get(&endpoint) else {
+                    return Err(format!("unknown SSE endpoint {}", endpoint));
+                };
+                let identity = context.identity.as_ref();
+                stream
+                    .auth
+                    .authorize(identity)
+                    .map_err(|e| e.message().to_string())?;
+                stream
+                    .rate_limit
+                    .check(identity, context.ip)
+                    .map_err(|_| "rate limit exceeded".to_string())?;
+                subscriptions
+                    .streams
+                    .insert(endpoint.clone(), query_string(params));
+
+                Ok(json!({"type": "subscribed", "endpoint": endpoint}))
+            }
+            _ => Err("subscribe to either a channel or an endpoint".to_string()),
+        }
+    }
+
+    /// Messages sent for a notification, one per subscription it concerns.
+    async fn notifications(
+        &self,
+        channel: String,
+        payload: String,
+        subscriptions: &Subscriptions,
+        context: &RequestContext,
+    ) -> Vec<Value> {
+        let mut messages = Vec::new();
+        for (endpoint, params) in &subscriptions.streams {
+            let Some(stream) = self.streams.get(endpoint) else {
+                continue;
+            };
+            if stream.handler.channel() != channel {
+                continue;
+            }
+            match stream
+                .handler
+                .render(payload.clone(), params, context, &stream.pool)
+                .await
+            {
+                Some(Ok(data)) => messages
+                    .push(json!({"type": "notification", "endpoint": endpoint, "data": data})),
+                Some(Err(e)) => messages.push(
+                    json!({"type": "error", "endpoint": endpoint, "error": format!("{}", e)}),
+                ),
+                None => {}
+            }
+        }
+        if subscriptions.channels.contains(&channel) {
+            messages.push(json!({"type": "notification", "channel": channel, "payload": payload}));
+        }
+
+        messages
+    }
+
+    async fn on_message(
+        &self,
+        text: &str,
+        subscriptions: &mut Subscriptions,
+        context: &RequestContext,
+    ) -> Value {
+        let message = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(m) => m,
+            Err(e) => return json!({"type": "error", "error": format!("{}", e)}),
+        };
+        // the token is checked by the handshake only
+        if context.identity.as_ref().is_some_and(Identity::expired) {
+            return json!({"type": "error", "error": "token expired"});
+        }
+
+        match message {
+            ClientMessage::Query {
+                id,
+                endpoint,
+                method,
+                params,
+            } => match self.query(&endpoint, &method, &params, context).await {
+                Ok(data) => json!({"id": id, "type": "result", "data": data}),
+                Err(error) => json!({"id": id, "type": "error", "error": error}),
+            },
+            ClientMessage::Subscribe {
+                channel,
+                endpoint,
+                params,
+            } => self
+                .subscribe(channel, endpoint, &params, subscriptions, context)
+                .unwrap_or_else(|error| json!({"type": "error", "error": error})),
+            ClientMessage::Unsubscribe { channel, endpoint } => {
+                if let Some(channel) = &channel {
+                    subscriptions.channels.remove(channel);
+                }
+                if let Some(endpoint) = &endpoint {
+                    subscriptions.streams.remove(endpoint);
+                }
+                json!({"type": "unsubscribed", "channel": channel, "endpoint": endpoint})
+            }
+        }
+    }
+
+    /// Messages are answered one at a time, notifications arriving meanwhile
+    /// are buffered by the hub.
+    async fn session(self: Arc<Self>, mut socket: WebSocket, context: RequestContext) {
+        let mut events = self.hub.subscribe();
+        let mut draining = self.draining.clone();
+        let mut subscriptions = Subscriptions::default();
+
+        loop {
+            let replies = tokio::select! {
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        vec![self.on_message(&text, &mut subscriptions, &context).await]
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // pings are answered by axum
+                    Some(Ok(_)) => Vec::new(),
+                },
+                event = events.recv() => match event {
+                    Ok(_) if !subscriptions.is_empty()
+                        && context.identity.as_ref().is_some_and(Identity::expired) =>
+                    {
+                        let _ = socket.send(Message::Close(None)).await;
+                        break;
+                    }
+                    Ok(NotifyEvent::Notification { channel, payload }) => {
+                        self.notifications(channel, payload, &subscriptions, &context).await
+                    }
+                    // subscribers refetch what they may have missed
+                    Ok(NotifyEvent::Reconnected) if !subscriptions.is_empty() => {
+                        vec![json!({"type": "reconnected"})]
+                    }
+                    Ok(NotifyEvent::Reconnected) => Vec::new(),
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("websocket client missed {} notifications", missed);
+                        Vec::new()
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                // the guard returned by wait_for must not be held across the send
+                _ = async { draining.wait_for(|d| *d).await.is_ok() } => {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            };
+
+            for reply in replies {
+                if socket
+                    .send(Message::Text(reply.to_string().into()))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_client_message() {
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"id": 1, "type": "query", "endpoint": "/test/numbers", "params": {"limit": 2}}"#,
+        )
+        .unwrap();
+        let ClientMessage::Query { method, params, .. } = message else {
+            panic!("not a query");
+        };
+        assert_eq!(method, "GET");
+        assert_eq!(query_string(&params)["limit"], "2");
+
+        assert!(matches!(
+            serde_json::from_str(r#"{"type": "subscribe", "channel": "orders"}"#),
+            Ok(ClientMessage::Subscribe {
+                channel: Some(_),
+                endpoint: None,
+                ..
+            })
+        ));
+        let message: ClientMessage = serde_json::from_str(
+            r#"{"type": "subscribe", "endpoint": "/test/orders", "params": {"customer_id": 7}}"#,
+        )
+        .unwrap();
+        let ClientMessage::Subscribe {
+            endpoint, params, ..
+        } = message
+        else {
+            panic!("not a subscribe");
+        };
+        assert_eq!(endpoint.as_deref(), Some("/test/orders"));
+        assert_eq!(query_string(&params)["customer_id"], "7");
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"type": "drop"}"#).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use axum::{
+    Json,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{MethodRouter, get},
+};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio::sync::watch;
+
+use crate::db::Datasources;
+
+/// How long the readiness probe waits for a connection.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DslInfo {
+    pub endpoints: usize,
+    /// hash of the endpoint files, tells the deployed DSL trees apart
+    pub revision: String,
+}
+
+impl DslInfo {
+    /// `files` are (path, content) pairs in any order.
+    pub fn new<'a>(files: impl Iterator<Item = (&'a str, &'a str)>) -> DslInfo {
+        let mut files: Vec<(&str, &str)> = files.collect();
+        files.sort();
+
+        let mut hasher = Sha256::new();
+        for (path, content) in &files {
+            hasher.update(path.as_bytes());
+            hasher.update([0]);
+            hasher.update(content.as_bytes());
+            hasher.update([0]);
+        }
+
+        DslInfo {
+            endpoints: files.len(),
+            revision: hex::encode(&hasher.finalize()[..6]),
+        }
+    }
+}
+
+/// Liveness and readiness probes, served outside of the DSL namespace.
+pub struct Health {
+    dsl: OnceLock<DslInfo>,
+    /// set once shutdown started, readiness fails and the streams end
+    draining: watch::Sender<bool>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            dsl: OnceLock::new(),
+            draining: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Health {
+    pub fn set_dsl(&self, info: DslInfo) {
+        let _ = self.dsl.set(info);
+    }
+
+    pub fn set_draining(&self) {
+        self.draining.send_replace(true);
+    }
+
+    /// Turns true once shutdown started.
+    pub fn draining(&self) -> watch::Receiver<bool> {
+        self.draining.subscribe()
+    }
+
+    async fn check_pool(pool: &PgPool) -> anyhow::Result<()> {
+        tokio::time::timeout(PROBE_TIMEOUT, async {
+            let mut conn = pool.acquire().await?;
+            sqlx::query("SELECT 1").execute(&mut *conn).await?;
+            anyhow::Ok(())
+        })
+        .await?
+    }
+
+    /// Checks the datasources one after the other, the first failing one is
+    /// reported.
+    async fn check_datasources(datasources: &Datasources) -> anyhow::Result<()> {
+        for (name, pool) in datasources.iter() {
+            Health::check_pool(pool)
+                .await
+                .map_err(|e| anyhow::anyhow!("datasource {}: {}", name, e))?;
+        }
+        Ok(())
+    }
+
+    async fn ready(&self, datasources: &Datasources) -> Response {
+        if *self.draining.borrow() {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({"status": "draining"})),
+            )
+                .into_response();
+        }
+        let Some(dsl) = self.dsl.get() else {
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({"status": "unavailable", "error": "DSL tree not loaded"})),
+            )
+                .into_response();
+        };
+
+        match Health::check_datasources(datasources).await {
+            Ok(()) => Json(json!({
+                "status": "ready",
+                "endpoints": dsl.endpoints,
+                "revision": dsl.revision,
+            }))
+            .into_response(),
+            Err(e) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({
+                    "status": "unavailable",
+                    "error": format!("database: {}", e),
+                    "endpoints": dsl.endpoints,
+                    "revision": dsl.revision,
+                })),
+            )
+                .into_response(),
+        }
+    }
+
+    pub fn liveness_route(self: &Arc<Self>) -> MethodRouter<Datasources> {
+        let health = self.clone();
+        get(|| async move {
+            let dsl = health.dsl.get();
+            Json(json!({
+                "status": "ok",
+                "endpoints": dsl.map(|d| d.endpoints),
+                "revision": dsl.map(|d| d.revision.clone()),
+            }))
+        })
+    }
+
+    pub fn readiness_route(self: &Arc<Self>) -> MethodRouter<Datasources> {
+        let health = self.clone();
+        get(
+            |State(datasources): State<Datasources>| async move { health.ready(&datasources).await },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dsl_revision() {
+        let a = DslInfo::new([("a.sql", "SELECT 1"), ("b.sql", "SELECT 2")].into_iter());
+        let b = DslInfo::new([("b.sql", "SELECT 2"), ("a.sql", "SELECT 1")].into_iter());
+        let c = DslInfo::new([("a.sql", "SELECT 1"), ("b.sql", "SELECT 3")].into_iter());
+
+        assert_eq!(a.endpoints, 2);
+        assert_eq!(a.revision, b.revision);
+        assert_ne!(a.revision, c.revision);
+        assert_eq!(a.revision.len(), 12);
+    }
+}
@@ -0,0 +1,152 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+/// Returned when a query cannot get a slot, answered with 503.
+#[derive(Debug)]
+pub struct Overloaded {
+    reason: &'static str,
+}
+
+impl std::fmt::Display for Overloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "endpoint overloaded: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Overloaded {}
+
+/// Caps the queries of an endpoint running at once, the others wait in a
+/// queue for at most `queue_timeout`. The slot of the endpoint is nested
+/// under the one of its datasource, shared by every endpoint on the pool.
+#[derive(Debug)]
+pub struct ConcurrencyLimit {
+    permits: Arc<Semaphore>,
+    datasource: Option<Arc<Semaphore>>,
+    max_queue: Option<usize>,
+    queue_timeout: Duration,
+    waiting: AtomicUsize,
+}
+
+/// Keeps `waiting` accurate however the wait ends, the request future may
+/// be dropped while queued.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max_concurrency: usize, max_queue: Option<usize>, queue_timeout: Duration) -> Self {
+        ConcurrencyLimit {
+            permits: Arc::new(Semaphore::new(max_concurrency)),
+            datasource: None,
+            max_queue,
+            queue_timeout,
+            waiting: AtomicUsize::new(0),
+        }
+    }
+
+    /// Slots shared by the endpoints of the datasource.
+    pub fn with_datasource(mut self, datasource: Arc<Semaphore>) -> Self {
+        self.datasource = Some(datasource);
+        self
+    }
+
+    /// The slots are released when the permit is dropped.
+    pub async fn acquire(&self) -> Result<Permit, Overloaded> {
+        let deadline = Instant::now() + self.queue_timeout;
+        let endpoint = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let queued = self.waiting.fetch_add(1, Ordering::Relaxed);
+                let _waiting = Waiting(&self.waiting);
+                if self.max_queue.is_some_and(|max| queued >= max) {
+                    return Err(Overloaded {
+                        reason: "queue is full",
+                    });
+                }
+                wait(&self.permits, deadline).await?
+            }
+        };
+
+        // requests of the endpoint queue above, at most `max_concurrency`
+        // of them wait for the datasource
+        let datasource = match &self.datasource {
+            Some(datasource) => Some(match datasource.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => wait(datasource, deadline).await?,
+            }),
+            None => None,
+        };
+
+        Ok(Permit {
+            _endpoint: endpoint,
+            _datasource: datasource,
+        })
+    }
+}
+
+/// Slot of the endpoint and of its datasource.
+#[derive(Debug)]
+pub struct Permit {
+    _endpoint: OwnedSemaphorePermit,
+    _datasource: Option<OwnedSemaphorePermit>,
+}
+
+async fn wait(
+    permits: &Arc<Semaphore>,
+    deadline: Instant,
+) -> Result<OwnedSemaphorePermit, Overloaded> {
+    match tokio::time::timeout_at(deadline, permits.clone().acquire_owned()).await {
+        Ok(Ok(permit)) => Ok(permit),
+        Ok(Err(_)) => Err(Overloaded {
+            reason: "endpoint is closed",
+        }),
+        Err(_) => Err(Overloaded {
+            reason: "timed out in queue",
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limit = ConcurrencyLimit::new(1, Some(0), Duration::from_millis(10));
+
+        let permit = limit.acquire().await.unwrap();
+        assert!(limit.acquire().await.is_err());
+        drop(permit);
+        assert!(limit.acquire().await.is_ok());
+
+        let limit = ConcurrencyLimit::new(1, None, Duration::from_millis(10));
+        let _permit = limit.acquire().await.unwrap();
+        let err = limit.acquire().await.unwrap_err();
+        assert_eq!(err.to_string(), "endpoint overloaded: timed out in queue");
+    }
+
+    #[tokio::test]
+    async fn test_datasource() {
+        let datasource = Arc::new(Semaphore::new(1));
+        let limit = |max| {
+            ConcurrencyLimit::new(max, None, Duration::from_millis(10))
+                .with_datasource(datasource.clone())
+        };
+        let (orders, customers) = (limit(2), limit(2));
+
+        // the endpoints have slots left, the datasource has not
+        let permit = orders.acquire().await.unwrap();
+        assert!(customers.acquire().await.is_err());
+        assert!(orders.acquire().await.is_err());
+        drop(permit);
+        assert!(customers.acquire().await.is_ok());
+    }
+}
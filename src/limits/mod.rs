@@ -0,0 +1,89 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use crate::access_log::error_body;
+use crate::auth::Identity;
+use crate::limits::rate_limit::{RateLimit, RateLimiter, retry_after_header};
+
+pub mod concurrency;
+pub mod rate_limit;
+
+/// State of the rate limit layer wrapped around a single endpoint handler.
+/// The layer sits inside the auth layer, so that authenticated callers are
+/// limited by their API key or JWT subject rather than their address.
+#[derive(Clone)]
+pub struct EndpointRateLimit {
+    limiter: Arc<RateLimiter>,
+    limit: Option<RateLimit>,
+    endpoint: String,
+}
+
+impl EndpointRateLimit {
+    pub fn new(limiter: Arc<RateLimiter>, limit: Option<RateLimit>, endpoint: String) -> Self {
+        EndpointRateLimit {
+            limiter,
+            limit,
+            endpoint,
+        }
+    }
+
+    /// Counts a call, the error is the time to wait before the next one.
+    pub fn check(&self, identity: Option<&Identity>, ip: Option<IpAddr>) -> Result<(), Duration> {
+        let Some(limit) = &self.limit else {
+            return Ok(());
+        };
+        let key = format!("{} {}", self.endpoint, caller_key(identity, ip));
+
+        self.limiter.check(&key, limit)
+    }
+}
+
+/// API key, then JWT subject, then client ip.
+fn caller_key(identity: Option<&Identity>, ip: Option<IpAddr>) -> String {
+    if let Some(key) = identity.and_then(|i| i.api_key.as_ref()) {
+        return format!("key:{}", key.name);
+    }
+    if let Some(sub) = identity.and_then(|i| i.claims.get("sub")) {
+        return format!("sub:{}", sub);
+    }
+
+    match ip {
+        Some(ip) => format!("ip:{}", ip),
+        None => "anonymous".to_string(),
+    }
+}
+
+pub async fn rate_limit_middleware(
+    State(rate_limit): State<EndpointRateLimit>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+
+    match rate_limit.check(request.extensions().get::<Identity>(), ip) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            let mut response = (
+                StatusCode::TOO_MANY_REQUESTS,
+                error_body("rate limit exceeded"),
+            )
+                .into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, retry_after_header(retry_after));
+
+            response
+        }
+    }
+}
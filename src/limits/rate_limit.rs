@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use axum::http::HeaderValue;
+use serde::Deserialize;
+
+/// Buckets are only swept once the map grows past this size.
+const SWEEP_THRESHOLD: usize = 10_000;
+/// and at most this often, so that the sweep is not paid on every request.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token bucket parameters written as `10/s`, `100/m burst 20` or `1000/h`.
+/// Without `burst` the bucket holds one period worth of requests.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct RateLimit {
+    /// tokens added per second
+    rate: f64,
+    /// bucket capacity
+    burst: f64,
+}
+
+impl TryFrom<String> for RateLimit {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl std::str::FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid rate limit {}, expected e.g. 10/s burst 20", s);
+
+        let mut parts = s.split_whitespace();
+        let (count, period) = parts
+            .next()
+            .and_then(|p| p.split_once('/'))
+            .ok_or_else(invalid)?;
+        let count: f64 = count.parse().map_err(|_| invalid())?;
+        let seconds = match period {
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            _ => return Err(invalid()),
+        };
+
+        let burst = match (parts.next(), parts.next(), parts.next()) {
+            (None, _, _) => count,
+            (Some("burst"), Some(burst), None) => burst.parse().map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+
+        if count <= 0.0 || burst < 1.0 {
+            return Err(invalid());
+        }
+
+        Ok(RateLimit {
+            rate: count / seconds,
+            burst,
+        })
+    }
+}
+
+/// `Retry-After` value in whole seconds, rounded up so the client does not
+/// retry too early.
+pub fn retry_after_header(retry_after: Duration) -> HeaderValue {
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    HeaderValue::from(seconds)
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<String, Bucket>,
+    swept: Option<Instant>,
+}
+
+impl Buckets {
+    /// Drops the full buckets, each refilled at its own limit.
+    fn sweep(&mut self, now: Instant) {
+        let due = self
+            .swept
+            .is_none_or(|swept| now.duration_since(swept) >= SWEEP_INTERVAL);
+        if self.by_key.len() <= SWEEP_THRESHOLD || !due {
+            return;
+        }
+
+        // full buckets carry no state worth keeping
+        self.by_key.retain(|_, b| {
+            b.refill(now);
+            b.tokens < b.limit.burst
+        });
+        self.swept = Some(now);
+    }
+}
+
+/// In-process token buckets, one per key (client ip, API key, subject...).
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Takes a token from the bucket of `key`, or returns how long to wait
+    /// until the next one is available.
+    pub fn check(&self, key: &str, limit: &RateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.sweep(now);
+
+        let bucket = buckets.by_key.entry(key.to_string()).or_insert(Bucket {
+            tokens: limit.burst,
+            updated: now,
+            limit: *limit,
+        });
+        bucket.refill(now);
+        bucket.limit = *limit;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "10/s burst 20".parse::<RateLimit>(),
+            Ok(RateLimit {
+                rate: 10.0,
+                burst: 20.0
+            })
+        );
+        assert_eq!(
+            "120/m".parse::<RateLimit>(),
+            Ok(RateLimit {
+                rate: 2.0,
+                burst: 120.0
+            })
+        );
+        assert!("10".parse::<RateLimit>().is_err());
+        assert!("10/d".parse::<RateLimit>().is_err());
+        assert!("10/s burst".parse::<RateLimit>().is_err());
+        assert!("0/s".parse::<RateLimit>().is_err());
+    }
+
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::default();
+        let limit: RateLimit = "1/h burst 2".parse().unwrap();
+
+        assert!(limiter.check("a", &limit).is_ok());
+        assert!(limiter.check("a", &limit).is_ok());
+        let retry_after = limiter.check("a", &limit).unwrap_err();
+        assert!(retry_after > Duration::from_secs(3500));
+
+        assert!(limiter.check("b", &limit).is_ok());
+    }
+
+    #[test]
+    fn test_sweep() {
+        let limiter = RateLimiter::default();
+        let slow: RateLimit = "1/h".parse().unwrap();
+        let fast: RateLimit = "1000/s".parse().unwrap();
+
+        for key in 0..=SWEEP_THRESHOLD {
+            assert!(limiter.check(&key.to_string(), &slow).is_ok());
+        }
+        // the sweep refills each bucket at its own limit, not the caller's
+        assert!(limiter.check("other", &fast).is_ok());
+        assert!(limiter.check("0", &slow).is_err());
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.swept.is_some());
+        assert_eq!(buckets.by_key.len(), SWEEP_THRESHOLD + 2);
+    }
+}
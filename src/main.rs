@@ -1,15 +1,31 @@
-use axum::{Router, extract::Request, middleware::Next, response::Response};
+use axum::Router;
 use log::{LevelFilter, info, warn};
 use log4rs;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Config, Root};
-use sqlx::PgPool;
-use std::time::Instant;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio;
 
+use crate::auth::Authenticator;
 use crate::endpoints::load_dsl_endpoints;
+use crate::health::Health;
+use crate::metrics::Metrics;
+mod access_log;
 mod args;
+mod auth;
+mod cache;
+mod db;
 mod endpoints;
+mod health;
+mod limits;
+mod metrics;
+mod notify;
+mod telemetry;
+
+/// How long the connections are given to close on shutdown.
+const POOL_CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 fn init_logging(args: &args::types::Args) -> Option<()> {
     match &args.log_config {
@@ -48,21 +64,62 @@ async fn init_and_run(args: &args::types::Args) {
 
     print_hello();
 
-    let pool;
-    match PgPool::connect(&args.db_uri).await {
-        Ok(r) => pool = r,
+    let tracer_provider = match telemetry::init(args) {
+        Ok(p) => p,
+        Err(e) => {
+            warn!("{}", e);
+            return;
+        }
+    };
+
+    let datasources;
+    match db::Datasources::connect(args).await {
+        Ok(r) => datasources = r,
         Err(e) => {
             warn!("{}", e);
             return;
         }
     }
 
+    let authenticator = match Authenticator::from_args(args, datasources.default_pool()) {
+        Ok(a) => Arc::new(a),
+        Err(e) => {
+            warn!("{}", e);
+            return;
+        }
+    };
+
+    let metrics = match Metrics::new() {
+        Ok(m) => Arc::new(m),
+        Err(e) => {
+            warn!("{}", e);
+            return;
+        }
+    };
+
     let port = args.port;
     let bind = args.bind.clone();
 
-    let app = Router::new().layer(axum::middleware::from_fn(uri_middleware));
-
-    let app = load_dsl_endpoints(&args, app).with_state(pool);
+    let health = Arc::new(Health::default());
+    let app = Router::new()
+        .route(&args.metrics_path, metrics.clone().route())
+        .route(&args.health_path, health.liveness_route())
+        .route(&args.ready_path, health.readiness_route());
+
+    let app = load_dsl_endpoints(
+        &args,
+        authenticator,
+        metrics,
+        health.clone(),
+        &datasources,
+        app,
+    )
+    .layer(axum::middleware::from_fn(telemetry::trace_middleware))
+    .layer(axum::middleware::from_fn_with_state(
+        args.access_log_format,
+        access_log::access_log_middleware,
+    ))
+    .with_state(datasources.clone());
 
     let listener;
     match tokio::net::TcpListener::bind(format!("{}:{}", bind, port)).await {
@@ -77,27 +134,84 @@ async fn init_and_run(args: &args::types::Args) {
     info!("Server startup completed in {:?}", duration);
     info!("Starting server at http://{}:{}", args.bind, args.port);
 
-    if let Err(e) = axum::serve(listener, app).await {
+    let service = app.into_make_service_with_connect_info::<SocketAddr>();
+    let draining = health.clone();
+    let server = axum::serve(listener, service)
+        .with_graceful_shutdown(async move {
+            shutdown_signal().await;
+            info!("Shutting down, waiting for in-flight requests");
+            draining.set_draining();
+        })
+        .into_future();
+    let mut draining = health.draining();
+    let deadline = async {
+        let _ = draining.wait_for(|d| *d).await;
+        tokio::time::sleep(args.shutdown_timeout).await;
+    };
+
+    tokio::select! {
+        res = server => {
+            if let Err(e) = res {
+                warn!("{}", e);
+            }
+            let _ = tokio::time::timeout(POOL_CLOSE_TIMEOUT, datasources.close()).await;
+        }
+        _ = deadline => {
+            warn!(
+                "Requests still running after {:?}, exiting",
+                args.shutdown_timeout
+            );
+            // closes the idle connections, the ones still in use are
+            // dropped with the runtime
+            let _ = tokio::time::timeout(POOL_CLOSE_TIMEOUT, datasources.close()).await;
+        }
+    }
+
+    if let Some(provider) = tracer_provider
+        && let Err(e) = provider.shutdown()
+    {
         warn!("{}", e);
     }
 }
 
-async fn uri_middleware(request: Request, next: Next) -> Response {
-    let uri = request.uri().clone();
-
-    let response = next.run(request).await;
-
-    info!("{} - {}", uri, response.status());
+/// SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("{}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                warn!("{}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    response
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
 
 fn main() {
     let args = args::types::get_args();
-    match tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-    {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    if let Some(workers) = args.workers {
+        builder.worker_threads(workers);
+    }
+    match builder.enable_all().build() {
         Ok(r) => r.block_on(async move { init_and_run(&args).await }),
         Err(e) => println!("{}", e),
     }
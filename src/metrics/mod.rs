@@ -0,0 +1,248 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{MethodRouter, get},
+};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::db::Datasources;
+
+/// Marks responses carrying an error body, which keep status 200 for
+/// compatibility and would not be counted as errors otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct Failed;
+
+/// Prometheus metrics of the DSL endpoints and the connection pools.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    errors: IntCounterVec,
+    duration: HistogramVec,
+    query_duration: HistogramVec,
+    /// the pool gauges are labelled with the datasource
+    pool_size: IntGaugeVec,
+    pool_idle: IntGaugeVec,
+    pool_waiters: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Metrics> {
+        let registry = Registry::new_custom(Some("rstsql".to_string()), None)?;
+        let endpoint_labels = ["project", "endpoint", "method"];
+
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests handled by the endpoints"),
+                &["project", "endpoint", "method", "status"],
+            )?,
+            errors: IntCounterVec::new(
+                Opts::new(
+                    "http_errors_total",
+                    "Requests answered with an error status or error body",
+                ),
+                &endpoint_labels,
+            )?,
+            duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time to answer the requests, auth and queueing included",
+                ),
+                &endpoint_labels,
+            )?,
+            query_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "query_duration_seconds",
+                    "Time spent running the endpoint SQL on an acquired connection",
+                ),
+                &["project", "endpoint"],
+            )?,
+            pool_size: IntGaugeVec::new(
+                Opts::new("pool_connections", "Connections open in the pool"),
+                &["datasource"],
+            )?,
+            pool_idle: IntGaugeVec::new(
+                Opts::new("pool_idle_connections", "Idle connections of the pool"),
+                &["datasource"],
+            )?,
+            pool_waiters: IntGaugeVec::new(
+                Opts::new("pool_waiters", "Queries waiting to acquire a connection"),
+                &["datasource"],
+            )?,
+            registry,
+        };
+
+        let collectors: [Box<dyn Collector>; 7] = [
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.errors.clone()),
+            Box::new(metrics.duration.clone()),
+            Box::new(metrics.query_duration.clone()),
+            Box::new(metrics.pool_size.clone()),
+            Box::new(metrics.pool_idle.clone()),
+            Box::new(metrics.pool_waiters.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+
+        Ok(metrics)
+    }
+
+    pub fn endpoint(
+        self: &Arc<Self>,
+        project: &str,
+        endpoint: &str,
+        method: &str,
+    ) -> EndpointMetrics {
+        EndpointMetrics {
+            metrics: self.clone(),
+            labels: [
+                project.to_string(),
+                endpoint.to_string(),
+                method.to_string(),
+            ],
+        }
+    }
+
+    pub fn query(&self, project: &str, endpoint: &str, datasource: &str) -> QueryMetrics {
+        QueryMetrics {
+            duration: self.query_duration.with_label_values(&[project, endpoint]),
+            waiters: self.pool_waiters.with_label_values(&[datasource]),
+        }
+    }
+
+    /// The pool gauges are sampled at scrape time.
+    fn render(&self, datasources: &Datasources) -> Response {
+        for (name, pool) in datasources.iter() {
+            self.pool_size
+                .with_label_values(&[name])
+                .set(pool.size() as i64);
+            self.pool_idle
+                .with_label_values(&[name])
+                .set(pool.num_idle() as i64);
+        }
+
+        let encoder = TextEncoder::new();
+        let mut body = Vec::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut body) {
+            return (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response();
+        }
+
+        (
+            [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+            body,
+        )
+            .into_response()
+    }
+
+    pub fn route(self: Arc<Self>) -> MethodRouter<Datasources> {
+        get(|State(datasources): State<Datasources>| async move { self.render(&datasources) })
+    }
+}
+
+/// State of the metrics layer wrapped around a single endpoint handler.
+#[derive(Clone)]
+pub struct EndpointMetrics {
+    metrics: Arc<Metrics>,
+    /// project, endpoint path and method
+    labels: [String; 3],
+}
+
+impl EndpointMetrics {
+    /// Records a call answered with `status`, `failed` when the body carries
+    /// an error.
+    pub fn observe(&self, status: StatusCode, failed: bool, elapsed: Duration) {
+        let [project, path, method] = &self.labels;
+        let labels = [project.as_str(), path.as_str(), method.as_str()];
+        self.metrics
+            .duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+        self.metrics
+            .requests
+            .with_label_values(&[project, path, method, status.as_str()])
+            .inc();
+        if status.is_client_error() || status.is_server_error() || failed {
+            self.metrics.errors.with_label_values(&labels).inc();
+        }
+    }
+}
+
+pub async fn metrics_middleware(
+    State(endpoint): State<EndpointMetrics>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    let response = next.run(request).await;
+
+    endpoint.observe(
+        response.status(),
+        response.extensions().get::<Failed>().is_some(),
+        start.elapsed(),
+    );
+
+    response
+}
+
+/// Query metrics of a single endpoint, recorded by its handler.
+#[derive(Clone)]
+pub struct QueryMetrics {
+    duration: Histogram,
+    waiters: IntGauge,
+}
+
+/// Counts a query as waiting for a connection until dropped.
+pub struct Waiting(IntGauge);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl QueryMetrics {
+    pub fn waiting(&self) -> Waiting {
+        self.waiters.inc();
+        Waiting(self.waiters.clone())
+    }
+
+    pub fn observe(&self, elapsed: Duration) {
+        self.duration.observe(elapsed.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_query_metrics() {
+        let metrics = Metrics::new().unwrap();
+        let query = metrics.query("test", "/test/numbers", "warehouse");
+        let waiters = metrics.pool_waiters.with_label_values(&["warehouse"]);
+
+        let waiting = query.waiting();
+        assert_eq!(waiters.get(), 1);
+        drop(waiting);
+        assert_eq!(waiters.get(), 0);
+
+        query.observe(Duration::from_millis(20));
+        let mut body = Vec::new();
+        TextEncoder::new()
+            .encode(&metrics.registry.gather(), &mut body)
+            .unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains(
+            r#"rstsql_query_duration_seconds_count{endpoint="/test/numbers",project="test"} 1"#
+        ));
+    }
+}
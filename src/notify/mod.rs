@@ -0,0 +1,109 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use sqlx::PgPool;
+use sqlx::postgres::PgListener;
+use tokio::sync::{broadcast, watch};
+
+/// Events buffered per subscriber before it starts missing them.
+const EVENTS_CAPACITY: usize = 1024;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub enum NotifyEvent {
+    Notification {
+        channel: String,
+        payload: String,
+    },
+    /// The listening connection was lost and established again,
+    /// notifications sent in the meantime are gone.
+    Reconnected,
+}
+
+/// Single `LISTEN` connection shared by everything interested in Postgres
+/// notifications, the events are fanned out to the subscribers.
+pub struct NotifyHub {
+    sender: broadcast::Sender<NotifyEvent>,
+}
+
+impl NotifyHub {
+    /// Listens to `channels` in a background task, nothing is started
+    /// without channels. The task stops once `draining` is set.
+    pub fn start(
+        pool: &PgPool,
+        channels: Vec<String>,
+        draining: watch::Receiver<bool>,
+    ) -> Arc<NotifyHub> {
+        let (sender, _) = broadcast::channel(EVENTS_CAPACITY);
+
+        if !channels.is_empty() {
+            info!("Listening to notifications on: {}", channels.join(", "));
+            tokio::spawn(listen(pool.clone(), channels, sender.clone(), draining));
+        }
+
+        Arc::new(NotifyHub { sender })
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<NotifyEvent> {
+        self.sender.subscribe()
+    }
+}
+
+/// The listener holds a connection of the pool, it is dropped on shutdown
+/// so that closing the pool does not wait for it.
+async fn listen(
+    pool: PgPool,
+    channels: Vec<String>,
+    sender: broadcast::Sender<NotifyEvent>,
+    mut draining: watch::Receiver<bool>,
+) {
+    let channels: Vec<&str> = channels.iter().map(|c| c.as_str()).collect();
+    let mut reconnecting = false;
+
+    while !pool.is_closed() && !*draining.borrow() {
+        let mut listener = match PgListener::connect_with(&pool).await {
+            Ok(l) => l,
+            Err(e) => {
+                warn!("Cannot connect the notification listener: {}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        if let Err(e) = listener.listen_all(channels.iter().copied()).await {
+            warn!("Cannot listen to notifications: {}", e);
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            continue;
+        }
+        if reconnecting {
+            let _ = sender.send(NotifyEvent::Reconnected);
+        }
+        reconnecting = true;
+
+        loop {
+            // sqlx listens to the channels again after a reconnect
+            let received = tokio::select! {
+                received = listener.try_recv() => received,
+                _ = draining.wait_for(|d| *d) => return,
+            };
+            let event = match received {
+                Ok(Some(n)) => NotifyEvent::Notification {
+                    channel: n.channel().to_string(),
+                    payload: n.payload().to_string(),
+                },
+                Ok(None) => {
+                    warn!("Notification listener reconnected, notifications may have been lost");
+                    NotifyEvent::Reconnected
+                }
+                Err(e) => {
+                    warn!("Notification listener failed: {}", e);
+                    break;
+                }
+            };
+            // no subscribers is not an error
+            let _ = sender.send(event);
+        }
+
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
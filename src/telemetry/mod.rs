@@ -0,0 +1,183 @@
+use std::future::Future;
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use log::info;
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{FutureExt, Span, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue, global};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+
+use crate::access_log::request_id;
+
+const TRACER: &str = "rstsql";
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+fn provider(endpoint: &str, service_name: &str) -> anyhow::Result<SdkTracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(service_name.to_string())
+                .build(),
+        )
+        .build())
+}
+
+/// Exports the spans over OTLP/HTTP when a collector is configured, the
+/// spans are no-ops otherwise. The provider must be shut down on exit to
+/// flush the pending spans.
+pub fn init(args: &crate::args::types::Args) -> anyhow::Result<Option<SdkTracerProvider>> {
+    let Some(endpoint) = &args.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let provider = provider(endpoint, &args.otlp_service_name)?;
+    global::set_tracer_provider(provider.clone());
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    info!("Exporting traces to {}", endpoint);
+
+    Ok(Some(provider))
+}
+
+/// Runs `future` in a child span of the current one.
+pub async fn with_span<T, F>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    future: F,
+) -> anyhow::Result<T>
+where
+    F: Future<Output = anyhow::Result<T>>,
+{
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(&tracer);
+    let cx = Context::current_with_span(span);
+
+    let out = future.with_context(cx.clone()).await;
+    if let Err(e) = &out {
+        cx.span().set_status(Status::error(e.to_string()));
+    }
+    cx.span().end();
+
+    out
+}
+
+pub fn with_span_sync<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+    let mut span = global::tracer(TRACER).start(name);
+    let out = f();
+    span.end();
+
+    out
+}
+
+/// Starts the span of the request, continuing the trace of an incoming
+/// `traceparent` header.
+pub async fn trace_middleware(request: Request, next: Next) -> Response {
+    let parent =
+        global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(request.headers())));
+    // the route template keeps the span names few, unmatched requests are
+    // named by their method only
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string());
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", request.method().to_string()),
+        KeyValue::new("url.path", request.uri().path().to_string()),
+    ];
+    let name = match &route {
+        Some(route) => {
+            attributes.push(KeyValue::new("http.route", route.clone()));
+            format!("{} {}", request.method(), route)
+        }
+        None => request.method().to_string(),
+    };
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let response = next.run(request).with_context(cx.clone()).await;
+
+    let span = cx.span();
+    if let Some(id) = request_id() {
+        span.set_attribute(KeyValue::new("http.request.id", id));
+    }
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        response.status().as_u16() as i64,
+    ));
+    if response.status().is_server_error() {
+        span.set_status(Status::error(response.status().to_string()));
+    }
+    span.end();
+
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{Router, body::Bytes, extract::State, routing::post};
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export_to_collector() {
+        let (sender, mut received) = mpsc::unbounded_channel::<Bytes>();
+        let collector = Router::new()
+            .route(
+                "/v1/traces",
+                post(
+                    |State(sender): State<mpsc::UnboundedSender<Bytes>>, body: Bytes| async move {
+                        let _ = sender.send(body);
+                    },
+                ),
+            )
+            .with_state(sender);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, collector).await });
+
+        let provider = provider(&format!("http://{}/v1/traces", addr), "rstsql-test").unwrap();
+        let tracer = opentelemetry::trace::TracerProvider::tracer(&provider, TRACER);
+        tracer.in_span("GET /test/numbers", |_| {});
+
+        tokio::task::spawn_blocking(move || provider.force_flush())
+            .await
+            .unwrap()
+            .unwrap();
+        let body = received.recv().await.unwrap();
+        // protobuf strings are stored as is
+        assert!(body.windows(17).any(|w| w == b"GET /test/numbers"));
+    }
+}
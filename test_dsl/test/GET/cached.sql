@@ -0,0 +1,17 @@
+/*
+declaration:
+  description: numbers up to the limit, served from the response cache for a minute
+  allowlist:
+    query:
+      - field: limit
+        type: integer
+  response:
+    fields:
+      - field: id
+        type: integer
+  cache:
+    ttl: 60s
+  # NOTIFY numbers_changed evicts the cached responses
+  invalidate_on: [numbers_changed]
+*/
+SELECT id FROM generate_series(1, :limit::INTEGER) AS id;
@@ -0,0 +1,13 @@
+/*
+declaration:
+  description: test filtering and ordering
+  filtering: true
+  response:
+    fields:
+      - field: id
+        type: integer
+      - field: status
+        type: string
+*/
+SELECT id, CASE WHEN id % 2 = 0 THEN 'even' ELSE 'odd' END AS status
+FROM generate_series(1, 10) AS id;
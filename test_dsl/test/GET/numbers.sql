@@ -0,0 +1,13 @@
+/*
+declaration:
+  description: numbers with the given remainder of division by two
+  allowlist:
+    query:
+      - field: remainder
+        type: integer
+  response:
+    fields:
+      - field: id
+        type: integer
+*/
+SELECT id FROM generate_series(1, 10) AS id WHERE id % 2 = :remainder::INTEGER;
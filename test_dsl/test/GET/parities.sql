@@ -0,0 +1,25 @@
+/*
+declaration:
+  description: test embedding of another endpoint
+  embed:
+    numbers:
+      endpoint: /test/numbers
+      params:
+        remainder: remainder
+  response:
+    fields:
+      - field: parity
+        type: string
+      - field: remainder
+        type: integer
+      - field: numbers
+        type: array
+        items:
+          type: object
+          fields:
+            - field: id
+              type: integer
+*/
+SELECT 'even' AS parity, 0 AS remainder
+UNION ALL
+SELECT 'odd', 1;
@@ -0,0 +1,13 @@
+/*
+declaration:
+  description: test request context parameters
+  response:
+    fields:
+      - field: sub
+        type: string
+      - field: tenant
+        type: string
+      - field: ip
+        type: string
+*/
+SELECT :_claims.sub::TEXT AS sub, :_header.x_tenant::TEXT AS tenant, :_ip::TEXT AS ip;
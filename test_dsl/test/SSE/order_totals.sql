@@ -0,0 +1,14 @@
+/*
+declaration:
+  description: id and total of each notified order, read from the json payload
+  channel: orders
+  heartbeat: 30s
+  response:
+    fields:
+      - field: id
+        type: integer
+      - field: total
+        type: number
+*/
+SELECT (p ->> 'id')::INTEGER AS id, (p ->> 'total')::NUMERIC AS total
+FROM (SELECT :_payload::JSONB AS p) AS notification;
@@ -0,0 +1,14 @@
+/*
+declaration:
+  description: >
+    orders as they are placed, NOTIFY orders with a json payload, e.g.
+    SELECT pg_notify('orders', '{"id": 1, "customer_id": 7}');
+    ?customer_id=7 only streams the orders of that customer
+  channel: orders
+  response:
+    fields:
+      - field: id
+        type: integer
+      - field: customer_id
+        type: integer
+*/